@@ -11,7 +11,7 @@ fn benchmarks(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::new("write", size), size, |b, &s| {
             let dir = tempdir().unwrap();
             let db_path = dir.path().join("bench_write.db");
-            let mut db = Database::new(&db_path);
+            let db = Database::new(&db_path);
             
             // Pre-fill to `size`
             let entries: Vec<(String, String)> = (0..s)
@@ -30,7 +30,7 @@ fn benchmarks(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::new("read", size), size, |b, &s| {
             let dir = tempdir().unwrap();
             let db_path = dir.path().join("bench_read.db");
-            let mut db = Database::new(&db_path);
+            let db = Database::new(&db_path);
             
             // Pre-fill
             let entries: Vec<(String, String)> = (0..s)
@@ -52,7 +52,7 @@ fn benchmarks(c: &mut Criterion) {
             
             // Pre-fill
             {
-                let mut db = Database::new(&db_path);
+                let db = Database::new(&db_path);
                 let entries: Vec<(String, String)> = (0..s)
                     .map(|i| (format!("key{}", i), "value".to_string()))
                     .collect();
@@ -68,7 +68,7 @@ fn benchmarks(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::new("get_by_prefix", size), size, |b, &s| {
             let dir = tempdir().unwrap();
             let db_path = dir.path().join("bench_prefix.db");
-            let mut db = Database::new(&db_path);
+            let db = Database::new(&db_path);
             
             // Pre-fill
             let entries: Vec<(String, String)> = (0..s)
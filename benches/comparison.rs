@@ -19,7 +19,7 @@ fn comparison_benchmarks(c: &mut Criterion) {
                 (dir, path) // Keep dir alive
             },
             |(_dir, path)| {
-                let mut db = Database::new(&path);
+                let db = Database::new(&path);
                 for i in 0..size {
                     db.set(format!("key{}", i), "value".to_string());
                 }
@@ -51,7 +51,7 @@ fn comparison_benchmarks(c: &mut Criterion) {
     let janql_dir = tempdir().unwrap();
     let janql_path = janql_dir.path().join("janql_read.db");
     {
-        let mut db = Database::new(&janql_path);
+        let db = Database::new(&janql_path);
         for i in 0..size {
             db.set(format!("key{}", i), "value".to_string());
         }
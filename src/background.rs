@@ -0,0 +1,157 @@
+//! Threads running flushes and compactions off the write path.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+thread_local! {
+    static IS_BACKGROUND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread is one of a [`BackgroundPool`]'s.
+pub(crate) fn is_background_thread() -> bool {
+    IS_BACKGROUND.get()
+}
+
+/// Work for the background threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Job {
+    /// Write the full memtables waiting to be flushed to tables.
+    Flush,
+    /// Run the compactions the compaction policy asks for.
+    Compaction,
+}
+
+/// Jobs waiting for a thread, shared by the database and its threads.
+#[derive(Default)]
+pub(crate) struct JobQueue {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Job>,
+    running: usize,
+    shutdown: bool,
+}
+
+impl JobQueue {
+    /// Queues `job`, unless one of its kind is already waiting to run and
+    /// will pick up whatever this one was for.
+    pub(crate) fn schedule(&self, job: Job) {
+        let mut queue = self.queue.lock().unwrap();
+        if !queue.pending.contains(&job) {
+            queue.pending.push_back(job);
+            self.changed.notify_all();
+        }
+    }
+
+    /// Blocks until no job is waiting or running.
+    pub(crate) fn wait_idle(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.pending.is_empty() || queue.running > 0 {
+            queue = self.changed.wait(queue).unwrap();
+        }
+    }
+
+    /// Takes the next job, waiting for one if there is none. Returns `None`
+    /// once the queue is shut down.
+    fn next(&self) -> Option<Job> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.shutdown {
+                return None;
+            }
+            if let Some(job) = queue.pending.pop_front() {
+                queue.running += 1;
+                return Some(job);
+            }
+            queue = self.changed.wait(queue).unwrap();
+        }
+    }
+
+    fn finish(&self) {
+        self.queue.lock().unwrap().running -= 1;
+        self.changed.notify_all();
+    }
+
+    /// Waits up to `timeout` for the queue to be shut down, and returns
+    /// whether it was.
+    fn wait_shut_down(&self, timeout: Duration) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let (queue, _) = self
+            .changed
+            .wait_timeout_while(queue, timeout, |q| !q.shutdown)
+            .unwrap();
+        queue.shutdown
+    }
+
+    fn shut_down(&self) {
+        self.queue.lock().unwrap().shutdown = true;
+        self.changed.notify_all();
+    }
+}
+
+/// A fixed set of threads taking jobs from a [`JobQueue`], plus one
+/// scheduling a [`Job::Compaction`] every `check_interval`, so that compaction
+/// policies are checked whether or not writes arrive.
+///
+/// Dropping the pool lets running jobs finish and joins the threads; jobs
+/// still waiting are dropped.
+pub(crate) struct BackgroundPool {
+    queue: Arc<JobQueue>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BackgroundPool {
+    pub(crate) fn start(
+        queue: Arc<JobQueue>,
+        threads: usize,
+        check_interval: Duration,
+        run: impl Fn(Job) + Send + Sync + 'static,
+    ) -> Self {
+        let run = Arc::new(run);
+        let mut threads: Vec<_> = (0..threads)
+            .map(|i| {
+                let queue = queue.clone();
+                let run = run.clone();
+                thread::Builder::new()
+                    .name(format!("janql-bg-{}", i))
+                    .spawn(move || {
+                        IS_BACKGROUND.set(true);
+                        while let Some(job) = queue.next() {
+                            run(job);
+                            queue.finish();
+                        }
+                    })
+                    .expect("Failed to spawn background thread")
+            })
+            .collect();
+
+        let scheduler = queue.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("janql-scheduler".to_string())
+                .spawn(move || {
+                    while !scheduler.wait_shut_down(check_interval) {
+                        scheduler.schedule(Job::Compaction);
+                    }
+                })
+                .expect("Failed to spawn scheduler thread"),
+        );
+
+        Self { queue, threads }
+    }
+}
+
+impl Drop for BackgroundPool {
+    fn drop(&mut self) {
+        self.queue.shut_down();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
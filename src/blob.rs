@@ -0,0 +1,188 @@
+//! Append-only blob files for key-value separation.
+//!
+//! Values at or above the configured threshold are written to
+//! `blob_<n>.blob` files when a table is built, and the table stores a
+//! [`BlobPointer`] in their place. Compaction then moves pointers around
+//! instead of rewriting the values themselves.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Blob files are rotated once they grow past this size.
+const BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+
+/// Size of an encoded [`BlobPointer`].
+pub(crate) const BLOB_POINTER_SIZE: usize = 8 + 8 + 4;
+
+/// Location of a value stored in a blob file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPointer {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl BlobPointer {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.file.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.len.to_le_bytes());
+    }
+
+    pub(crate) fn decode(buf: &[u8; BLOB_POINTER_SIZE]) -> Self {
+        Self {
+            file: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+        }
+    }
+}
+
+struct ActiveBlobFile {
+    number: u64,
+    file: File,
+    len: u64,
+    dirty: bool,
+}
+
+/// The set of blob files in a database directory.
+pub struct BlobStore {
+    dir: PathBuf,
+    active: Mutex<Option<ActiveBlobFile>>,
+    next_number: Mutex<u64>,
+    readers: Mutex<HashMap<u64, File>>,
+}
+
+impl BlobStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let next_number = list_blob_files(&dir)?
+            .into_iter()
+            .max()
+            .map_or(1, |n| n + 1);
+
+        Ok(Self {
+            dir,
+            active: Mutex::new(None),
+            next_number: Mutex::new(next_number),
+            readers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn file_path(&self, number: u64) -> PathBuf {
+        blob_file_path(&self.dir, number)
+    }
+
+    /// Appends `value` to the active blob file.
+    ///
+    /// The value is not durable until [`seal`](Self::seal) returns.
+    pub fn put(&self, value: &str) -> io::Result<BlobPointer> {
+        let mut active = self.active.lock().unwrap();
+
+        if active.as_ref().is_none_or(|a| a.len >= BLOB_FILE_SIZE) {
+            if let Some(full) = active.as_mut()
+                && full.dirty
+            {
+                full.file.sync_data()?;
+            }
+
+            let number = {
+                let mut next = self.next_number.lock().unwrap();
+                let number = *next;
+                *next += 1;
+                number
+            };
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.file_path(number))?;
+            *active = Some(ActiveBlobFile {
+                number,
+                file,
+                len: 0,
+                dirty: false,
+            });
+        }
+
+        let active = active.as_mut().unwrap();
+        active.file.write_all(value.as_bytes())?;
+        let pointer = BlobPointer {
+            file: active.number,
+            offset: active.len,
+            len: value.len() as u32,
+        };
+        active.len += value.len() as u64;
+        active.dirty = true;
+
+        Ok(pointer)
+    }
+
+    /// Makes every value written so far durable and closes the active file,
+    /// so that the next value starts a new one.
+    ///
+    /// Called whenever a table that points at the values is finished, which
+    /// keeps each blob file tied to the few tables written alongside it.
+    pub fn seal(&self) -> io::Result<()> {
+        if let Some(active) = self.active.lock().unwrap().take()
+            && active.dirty
+        {
+            active.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, pointer: BlobPointer) -> io::Result<String> {
+        let mut readers = self.readers.lock().unwrap();
+        let file = match readers.entry(pointer.file) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(File::open(self.file_path(pointer.file))?),
+        };
+
+        let mut buf = vec![0u8; pointer.len as usize];
+        file.seek(SeekFrom::Start(pointer.offset))?;
+        file.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Number of the file currently receiving writes, if any.
+    pub fn active_file(&self) -> Option<u64> {
+        self.active.lock().unwrap().as_ref().map(|a| a.number)
+    }
+
+    /// Numbers of all blob files on disk.
+    pub fn files(&self) -> io::Result<Vec<u64>> {
+        list_blob_files(&self.dir)
+    }
+
+    /// Deletes a blob file that no table references any more.
+    pub fn remove_file(&self, number: u64) -> io::Result<()> {
+        self.readers.lock().unwrap().remove(&number);
+        fs::remove_file(self.file_path(number))
+    }
+}
+
+fn blob_file_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("blob_{:06}.blob", number))
+}
+
+fn list_blob_files(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "blob")
+            && let Some(number) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.strip_prefix("blob_"))
+                .and_then(|s| s.parse().ok())
+        {
+            numbers.push(number);
+        }
+    }
+    numbers.sort();
+    Ok(numbers)
+}
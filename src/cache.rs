@@ -0,0 +1,150 @@
+//! Size-bounded LRU cache for SSTable blocks, shared by every reader of a
+//! database.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const NUM_SHARDS: usize = 16;
+
+/// Identifies a block by the reader's file id and the block's offset.
+pub type BlockKey = (u64, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub usage: usize,
+    pub capacity: usize,
+}
+
+/// Sharded LRU cache of raw blocks.
+///
+/// The capacity is split evenly across shards, and each shard evicts its
+/// least recently used blocks independently.
+pub struct BlockCache {
+    shards: Vec<Mutex<LruShard>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        let shard_capacity = capacity.div_ceil(NUM_SHARDS);
+        Self {
+            shards: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(LruShard::new(shard_capacity)))
+                .collect(),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: BlockKey) -> Option<Arc<Vec<u8>>> {
+        let block = self.shard(key).lock().unwrap().get(key);
+        match block {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        block
+    }
+
+    pub fn insert(&self, key: BlockKey, block: Arc<Vec<u8>>) {
+        self.shard(key).lock().unwrap().insert(key, block);
+    }
+
+    /// Drops every cached block belonging to `file_id`.
+    pub fn evict_file(&self, file_id: u64) {
+        for shard in &self.shards {
+            shard.lock().unwrap().evict_file(file_id);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            usage: self.shards.iter().map(|s| s.lock().unwrap().usage).sum(),
+            capacity: self.capacity,
+        }
+    }
+
+    fn shard(&self, (file_id, offset): BlockKey) -> &Mutex<LruShard> {
+        // Cheap mix so that consecutive blocks of one file spread across shards
+        let hash = file_id
+            .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            .wrapping_add(offset / 512);
+        &self.shards[(hash as usize) % NUM_SHARDS]
+    }
+}
+
+struct LruShard {
+    capacity: usize,
+    usage: usize,
+    tick: u64,
+    entries: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    recency: BTreeMap<u64, BlockKey>, // Tick -> Key, oldest first
+}
+
+impl LruShard {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            usage: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let (block, tick) = self.entries.get_mut(&key)?;
+        self.recency.remove(tick);
+        *tick = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Arc<Vec<u8>>) {
+        if block.len() > self.capacity {
+            return;
+        }
+
+        self.remove(key);
+        self.tick += 1;
+        self.usage += block.len();
+        self.entries.insert(key, (block, self.tick));
+        self.recency.insert(self.tick, key);
+
+        while self.usage > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((block, _)) = self.entries.remove(&oldest) {
+                self.usage -= block.len();
+            }
+        }
+    }
+
+    fn remove(&mut self, key: BlockKey) {
+        if let Some((block, tick)) = self.entries.remove(&key) {
+            self.recency.remove(&tick);
+            self.usage -= block.len();
+        }
+    }
+
+    fn evict_file(&mut self, file_id: u64) {
+        let keys: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|(id, _)| *id == file_id)
+            .copied()
+            .collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+}
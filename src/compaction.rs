@@ -0,0 +1,492 @@
+//! Choosing which tables to merge.
+//!
+//! Tables live in levels. Flushes and ingestion add tables to level 0, where
+//! key ranges may overlap and newer tables shadow older ones. Every deeper
+//! level is a single sorted run: its tables hold disjoint key ranges, and all
+//! of them are older than the tables in the levels above.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sstable::SSTableReader;
+
+/// Upper bound for key ranges of tables that don't record theirs.
+const MAX_KEY: &str = "\u{10FFFF}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    Disabled,
+    /// Merge every table into one sorted run once this long has passed
+    /// since the last compaction.
+    Periodic(Duration),
+    /// Keep each level below a size limit by merging one table at a time
+    /// into the next level.
+    ///
+    /// Level 0 is compacted into level 1 once it holds `l0_trigger` tables.
+    /// Level 1 may hold `base_level_size` bytes, and each deeper level
+    /// `level_multiplier` times as much as the one above it.
+    Leveled {
+        l0_trigger: usize,
+        level_multiplier: u64,
+        base_level_size: u64,
+    },
+    /// Merge runs of level 0 tables of similar size.
+    ///
+    /// Going from newest to oldest, a table joins the current bucket if its
+    /// size is within `bucket_ratio` of the bucket's average, either way.
+    /// Buckets of at least `min_threshold` tables are merged, up to
+    /// `max_threshold` at a time, into a single table in their place.
+    /// Buckets only ever hold tables adjacent in age, so that the merged
+    /// table shadows exactly the tables its inputs did.
+    SizeTiered {
+        min_threshold: usize,
+        max_threshold: usize,
+        bucket_ratio: f64,
+    },
+    /// Never merge, and delete the oldest tables once all of them together
+    /// take more than `max_total_size` bytes, or once their newest data is
+    /// older than `max_age`. Meant for data that is only ever appended.
+    Fifo {
+        max_total_size: Option<u64>,
+        max_age: Option<Duration>,
+    },
+    /// Merge level 0 tables whose newest data falls into the same time
+    /// window of length `window`.
+    ///
+    /// The newest window is merged once it holds `min_threshold` tables, and
+    /// every older one as soon as it holds more than one, so that each past
+    /// window ends up as a single table.
+    TimeWindow {
+        window: Duration,
+        min_threshold: usize,
+    },
+    /// Compact whenever one of the set [`CompactionTriggers`] fires.
+    Triggered(CompactionTriggers),
+}
+
+/// Conditions for [`CompactionPolicy::Triggered`]. Any number of them can be
+/// set, and compaction runs as soon as one fires; unset ones never do.
+///
+/// All but `max_tombstone_ratio` merge every table into one sorted run in
+/// the deepest level, like [`Database::compact`](crate::Database::compact).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompactionTriggers {
+    /// Fires once level 0 holds more than this many tables.
+    pub max_tables: Option<usize>,
+    /// Fires once all tables together take more than this many times the
+    /// space of the oldest sorted run: the deepest level, or the oldest
+    /// table while everything is still in level 0.
+    pub max_size_ratio: Option<f64>,
+    /// Fires once gets have had to search more than this many tables on
+    /// average since the last full compaction.
+    pub max_read_amplification: Option<f64>,
+    /// Fires once this long has passed since the last full compaction.
+    pub interval: Option<Duration>,
+    /// Fires for a table once more than this fraction of its entries are
+    /// tombstones. Only that table is compacted, along with every table
+    /// overlapping it, so its tombstones can be dropped.
+    pub max_tombstone_ratio: Option<f64>,
+}
+
+/// What a [`CompactionFilter`] does with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Delete the key, as if it had been deleted with
+    /// [`Database::del`](crate::Database::del).
+    Remove,
+    /// Replace the value.
+    ChangeValue(String),
+}
+
+/// Application-defined rewriting of entries as compaction merges them.
+///
+/// Set through [`Options::compaction_filter`](crate::Options::compaction_filter),
+/// it sees the newest version of every live key in the tables being merged;
+/// shadowed versions and deleted keys are not passed to it. Entries still in
+/// the memtable or in tables no compaction has touched yet keep being read as
+/// they were written, so the filter should only make changes readers are
+/// fine seeing late.
+pub trait CompactionFilter: fmt::Debug + Send + Sync {
+    /// Decides what happens to `key`, which compaction is writing to `level`.
+    fn filter(&self, level: u32, key: &str, value: &str) -> FilterDecision;
+}
+
+/// Steps of writing and swapping in new tables, where tests can make the
+/// database stop as if the process had crashed.
+///
+/// With [`Options::crash_point`](crate::Options::crash_point) set, the
+/// operation returns an error right after the step, leaving the files as a
+/// crash would. Only built with the `failpoints` feature, for tests.
+#[cfg(feature = "failpoints")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// New tables are complete, under their temporary names.
+    TablesWritten,
+    /// New tables have their final names, but aren't in the manifest.
+    TablesRenamed,
+    /// The manifest lists a compaction's outputs instead of its inputs.
+    ManifestWritten,
+    /// The first of a compaction's inputs has been deleted.
+    InputDeleted,
+}
+
+/// Tables to merge, and where the result goes.
+#[derive(Debug)]
+pub(crate) struct CompactionJob {
+    /// Positions in the table list, in precedence order.
+    pub inputs: Vec<usize>,
+    pub output_level: u32,
+    /// Whether no table below the output level can hold the inputs' keys,
+    /// so that tombstones have nothing left to shadow.
+    pub drop_tombstones: bool,
+    /// Whether the output may be split into files of `target_file_size`.
+    pub split_output: bool,
+    /// Level and largest key of the table picked from a level above 0, where
+    /// the next compaction of that level picks up.
+    pub cursor: Option<(u32, String)>,
+}
+
+/// Key range of a table, assuming the widest one if it isn't recorded.
+pub(crate) fn key_range(table: &SSTableReader) -> (&str, &str) {
+    table
+        .properties()
+        .map_or(("", MAX_KEY), |p| (&p.smallest_key, &p.largest_key))
+}
+
+/// When the table's newest data was written, in microseconds since the Unix
+/// epoch.
+///
+/// Tables that don't record it fall back to the time in their file name,
+/// which is when they were written.
+pub(crate) fn creation_time(table: &SSTableReader) -> u64 {
+    match table.properties() {
+        Some(p) if p.creation_time > 0 => p.creation_time,
+        _ => table
+            .path()
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| {
+                stem.split('_')
+                    .filter(|part| part.len() > 4)
+                    .find_map(|part| part.parse().ok())
+            })
+            .unwrap_or(0),
+    }
+}
+
+/// Picks the level most over its limit and the tables to compact out of it.
+///
+/// `tables` must be in precedence order. `cursors` holds, per level, the
+/// largest key of the table compacted out of it last, so that tables are
+/// picked round-robin through the key space.
+pub(crate) fn pick_leveled(
+    tables: &[Arc<SSTableReader>],
+    l0_trigger: usize,
+    level_multiplier: u64,
+    base_level_size: u64,
+    cursors: &HashMap<u32, String>,
+) -> Option<CompactionJob> {
+    let max_level = tables.iter().map(|t| t.level()).max().unwrap_or(0);
+
+    let mut best: Option<(f64, u32)> = None;
+    for level in 0..=max_level {
+        let score = if level == 0 {
+            let count = tables.iter().filter(|t| t.level() == 0).count();
+            count as f64 / l0_trigger.max(1) as f64
+        } else {
+            let size: u64 = level_tables(tables, level)
+                .map(|(_, t)| t.file_size())
+                .sum();
+            let limit = base_level_size.max(1) as f64
+                * (level_multiplier.max(1) as f64).powi(level as i32 - 1);
+            size as f64 / limit
+        };
+
+        if score >= 1.0 && best.is_none_or(|(s, _)| score > s) {
+            best = Some((score, level));
+        }
+    }
+    let (_, level) = best?;
+
+    let (mut inputs, cursor) = if level == 0 {
+        (level_tables(tables, 0).map(|(i, _)| i).collect(), None)
+    } else {
+        let mut candidates = level_tables(tables, level);
+        let first = candidates.clone().next()?;
+        let (i, table) = match cursors.get(&level) {
+            Some(last) => candidates
+                .find(|(_, t)| key_range(t).0 > last.as_str())
+                .unwrap_or(first),
+            None => first,
+        };
+        (vec![i], Some((level, key_range(table).1.to_string())))
+    };
+
+    let (start, end) = range_of(tables, &inputs);
+    let output_level = level + 1;
+    inputs.extend(
+        level_tables(tables, output_level)
+            .filter(|(_, t)| t.overlaps(start, end))
+            .map(|(i, _)| i),
+    );
+
+    let drop_tombstones = !tables
+        .iter()
+        .any(|t| t.level() > output_level && t.overlaps(start, end));
+
+    Some(CompactionJob {
+        inputs,
+        output_level,
+        drop_tombstones,
+        split_output: true,
+        cursor,
+    })
+}
+
+/// Picks the newest bucket of level 0 tables that is due for a merge.
+pub(crate) fn pick_size_tiered(
+    tables: &[Arc<SSTableReader>],
+    min_threshold: usize,
+    max_threshold: usize,
+    bucket_ratio: f64,
+) -> Option<CompactionJob> {
+    let min_threshold = min_threshold.max(2);
+    let max_threshold = max_threshold.max(min_threshold);
+    let level_0: Vec<(usize, u64)> = level_tables(tables, 0)
+        .map(|(i, t)| (i, t.file_size()))
+        .collect();
+
+    let mut start = 0;
+    while start < level_0.len() {
+        let mut end = start + 1;
+        let mut total = level_0[start].1;
+        while end < level_0.len() {
+            let average = total as f64 / (end - start) as f64;
+            let size = level_0[end].1 as f64;
+            if size > average * bucket_ratio || size < average / bucket_ratio {
+                break;
+            }
+            total += level_0[end].1;
+            end += 1;
+        }
+
+        if end - start >= min_threshold {
+            let inputs = &level_0[start..end.min(start + max_threshold)];
+            return Some(merge_in_place(
+                tables,
+                inputs.iter().map(|&(i, _)| i).collect(),
+            ));
+        }
+        start = end;
+    }
+    None
+}
+
+/// Picks the newest time window of level 0 tables that is due for a merge.
+pub(crate) fn pick_time_window(
+    tables: &[Arc<SSTableReader>],
+    window: Duration,
+    min_threshold: usize,
+) -> Option<CompactionJob> {
+    let window = window.as_micros().max(1) as u64;
+    let level_0: Vec<(usize, u64)> = level_tables(tables, 0)
+        .map(|(i, t)| (i, creation_time(t) / window))
+        .collect();
+    let newest = level_0.iter().map(|&(_, w)| w).max()?;
+
+    // Level 0 is in precedence order, so a window's tables are adjacent
+    // unless its data arrived out of order; only adjacent ones are merged
+    let mut start = 0;
+    while start < level_0.len() {
+        let bucket = level_0[start].1;
+        let end = start
+            + level_0[start..]
+                .iter()
+                .take_while(|&&(_, w)| w == bucket)
+                .count();
+        let threshold = if bucket == newest { min_threshold } else { 2 };
+        if end - start >= threshold.max(2) {
+            let inputs = level_0[start..end].iter().map(|&(i, _)| i).collect();
+            return Some(merge_in_place(tables, inputs));
+        }
+        start = end;
+    }
+    None
+}
+
+/// Picks the tables for FIFO compaction to delete: the oldest ones, for as
+/// long as the tables left would take more than `max_total_size`, plus any
+/// whose newest data is older than `max_age`.
+///
+/// Returns positions in `tables`, which must be in precedence order.
+pub(crate) fn pick_fifo(
+    tables: &[Arc<SSTableReader>],
+    max_total_size: Option<u64>,
+    max_age: Option<Duration>,
+) -> Vec<usize> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64);
+    let expired = |table: &SSTableReader| {
+        max_age.is_some_and(|age| now.saturating_sub(creation_time(table)) > age.as_micros() as u64)
+    };
+
+    let mut total: u64 = tables.iter().map(|t| t.file_size()).sum();
+    let mut dropped = Vec::new();
+    for (i, table) in tables.iter().enumerate().rev() {
+        if max_total_size.is_some_and(|max| total > max) || expired(table) {
+            total -= table.file_size();
+            dropped.push(i);
+        }
+    }
+    dropped.reverse();
+    dropped
+}
+
+/// Picks every table holding keys in `[start, end]` for a merge into
+/// `output_level`, which must be at least as deep as any table's.
+///
+/// The range grows to cover the picked tables' own ranges until no table
+/// outside the job overlaps it, so the output never overlaps a table it
+/// doesn't replace, and its tombstones have nothing left to shadow.
+pub(crate) fn pick_range(
+    tables: &[Arc<SSTableReader>],
+    start: &str,
+    end: &str,
+    output_level: u32,
+) -> Option<CompactionJob> {
+    let (mut start, mut end) = (start, end);
+    let mut inputs = Vec::new();
+    loop {
+        let picked: Vec<usize> = (0..tables.len())
+            .filter(|&i| tables[i].overlaps(start, end))
+            .collect();
+        if picked.len() == inputs.len() {
+            break;
+        }
+        inputs = picked;
+        let (first, last) = range_of(tables, &inputs);
+        (start, end) = (start.min(first), end.max(last));
+    }
+    if inputs.is_empty() {
+        return None;
+    }
+
+    Some(CompactionJob {
+        inputs,
+        output_level,
+        drop_tombstones: true,
+        split_output: true,
+        cursor: None,
+    })
+}
+
+/// Whether one of the triggers calling for a full compaction fires.
+///
+/// `read_amplification` is the average number of tables gets have searched
+/// since the last full compaction, which was at `last_compaction`.
+pub(crate) fn full_compaction_due(
+    tables: &[Arc<SSTableReader>],
+    triggers: &CompactionTriggers,
+    read_amplification: f64,
+    last_compaction: SystemTime,
+) -> bool {
+    if tables.is_empty() {
+        return false;
+    }
+
+    let level_0 = level_tables(tables, 0).count();
+    if triggers.max_tables.is_some_and(|max| level_0 > max) {
+        return true;
+    }
+
+    if let Some(max) = triggers.max_size_ratio {
+        let max_level = tables.iter().map(|t| t.level()).max().unwrap_or(0);
+        let oldest_run: u64 = match max_level {
+            0 => tables.last().map_or(0, |t| t.file_size()),
+            level => level_tables(tables, level)
+                .map(|(_, t)| t.file_size())
+                .sum(),
+        };
+        let total: u64 = tables.iter().map(|t| t.file_size()).sum();
+        if total as f64 > oldest_run.max(1) as f64 * max {
+            return true;
+        }
+    }
+
+    if triggers
+        .max_read_amplification
+        .is_some_and(|max| read_amplification > max)
+    {
+        return true;
+    }
+
+    triggers
+        .interval
+        .is_some_and(|interval| last_compaction.elapsed().is_ok_and(|e| e >= interval))
+}
+
+/// Picks the first table with more than `max_ratio` of tombstones, and the
+/// tables to merge it with so that they can be dropped.
+pub(crate) fn pick_tombstone_dense(
+    tables: &[Arc<SSTableReader>],
+    max_ratio: f64,
+    output_level: u32,
+) -> Option<CompactionJob> {
+    let table = tables.iter().find(|t| {
+        t.properties().is_some_and(|p| {
+            p.num_entries > 0 && p.num_tombstones as f64 > p.num_entries as f64 * max_ratio
+        })
+    })?;
+    let (start, end) = key_range(table);
+    pick_range(tables, start, end, output_level)
+}
+
+/// A job merging adjacent level 0 tables into one table in their place.
+fn merge_in_place(tables: &[Arc<SSTableReader>], inputs: Vec<usize>) -> CompactionJob {
+    // Tombstones must stay while any older table may hold their keys
+    let (first, last) = range_of(tables, &inputs);
+    let oldest = *inputs.last().unwrap();
+    let drop_tombstones = !tables[oldest + 1..].iter().any(|t| t.overlaps(first, last));
+
+    CompactionJob {
+        inputs,
+        output_level: 0,
+        drop_tombstones,
+        split_output: false,
+        cursor: None,
+    }
+}
+
+fn level_tables(
+    tables: &[Arc<SSTableReader>],
+    level: u32,
+) -> impl Iterator<Item = (usize, &Arc<SSTableReader>)> + Clone {
+    tables
+        .iter()
+        .enumerate()
+        .filter(move |(_, t)| t.level() == level)
+}
+
+/// Smallest and largest key over the tables at `positions`.
+fn range_of<'a>(tables: &'a [Arc<SSTableReader>], positions: &[usize]) -> (&'a str, &'a str) {
+    positions
+        .iter()
+        .map(|&i| key_range(&tables[i]))
+        .reduce(|(s1, e1), (s2, e2)| (s1.min(s2), e1.max(e2)))
+        .unwrap_or(("", ""))
+}
+
+/// Orders tables by level, level 0 newest first and deeper levels by key.
+pub(crate) fn sort_tables(tables: &mut [Arc<SSTableReader>]) {
+    fn sort_key(table: &SSTableReader) -> (u32, &str) {
+        match table.level() {
+            0 => (0, ""),
+            level => (level, key_range(table).0),
+        }
+    }
+    tables.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+}
@@ -1,330 +1,2021 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
-
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::background::{self, BackgroundPool, Job, JobQueue};
+use crate::blob::BlobStore;
+use crate::cache::{BlockCache, CacheStats};
+#[cfg(feature = "failpoints")]
+use crate::compaction::CrashPoint;
+use crate::compaction::{self, CompactionJob, FilterDecision};
+use crate::manifest::{Manifest, TableEntry};
 use crate::memtable::MemTable;
-use crate::sstable::{SSTableBuilder, SSTableReader, SearchResult};
-use crate::wal::{WAL, WALIterator};
-use std::time::Duration;
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum CompactionPolicy {
-    Disabled,
-    Periodic(Duration),
+use crate::options::{Options, WriteOptions};
+use crate::sstable::{SSTableBuilder, SSTableReader, SearchResult, TableProperties, TableValue};
+use crate::wal::{self, RecoveryReport, WAL, WALIterator, WalRecord, WalStats};
+
+pub use crate::compaction::CompactionPolicy;
+
+/// How far [`Database::restore_to`] replays the logged writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreTarget {
+    /// Up to and including the write with this sequence number.
+    Sequence(u64),
+    /// Every write committed at or before this time.
+    Timestamp(SystemTime),
 }
 
+impl RestoreTarget {
+    fn includes(&self, record: &WalRecord) -> bool {
+        match *self {
+            RestoreTarget::Sequence(sequence) => record.sequence <= sequence,
+            RestoreTarget::Timestamp(time) => record.timestamp <= micros(time),
+        }
+    }
+
+    /// Whether every write up to the target has a sequence of at most
+    /// `sequence`, which only a sequence target tells without the next write.
+    fn reached(&self, sequence: u64) -> bool {
+        matches!(*self, RestoreTarget::Sequence(target) if target <= sequence)
+    }
+
+    /// Whether the target lies before the newest write of a checkpoint,
+    /// which has `sequence` and was logged at `timestamp`.
+    fn precedes(&self, sequence: u64, timestamp: u64) -> bool {
+        match *self {
+            RestoreTarget::Sequence(target) => target < sequence,
+            RestoreTarget::Timestamp(time) => micros(time) < timestamp,
+        }
+    }
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+/// A live SSTable as listed by [`Database::tables`].
+#[derive(Debug, Clone)]
+pub struct TableInfo {
+    pub name: String,
+    pub level: u32,
+    pub file_size: u64,
+    pub properties: Option<TableProperties>,
+}
+
+/// A handle to an open database.
+///
+/// The handle can be shared across threads. Writes that arrive while another
+/// one is being logged are committed together: one writer appends all of
+/// their records to the WAL with a single write and sync, and wakes the
+/// others once they are durable.
+///
+/// Full memtables are flushed and compactions run on
+/// [`Options::background_threads`] threads, which are joined when the handle
+/// is dropped.
 pub struct Database {
     pub path: PathBuf,
+    inner: Arc<Inner>,
+    _pool: Option<BackgroundPool>,
+}
+
+/// The database itself, shared by the handle and the background threads.
+struct Inner {
+    path: PathBuf,
+    /// Lock order: `wal`, then `flush_lock`, then `state`.
+    wal: Mutex<WAL>,
+    /// Held while memtables are written to tables, so that they are added
+    /// in the order they were filled.
+    flush_lock: Mutex<()>,
+    state: Mutex<State>,
+    /// Signalled with `state` whenever a flush or compaction finishes.
+    job_done: Condvar,
+    commits: Mutex<CommitQueue>,
+    committed: Condvar,
+    /// `None` if flushes and compactions run on the write path.
+    jobs: Option<Arc<JobQueue>>,
+    block_cache: Arc<BlockCache>,
+    blob_store: Arc<BlobStore>,
+    read_stats: ReadStats,
+    options: Options,
+    recovery_report: RecoveryReport,
+}
+
+/// Tables searched by gets since the last full compaction.
+#[derive(Default)]
+struct ReadStats {
+    gets: AtomicU64,
+    probes: AtomicU64,
+}
+
+impl ReadStats {
+    fn amplification(&self) -> f64 {
+        let gets = self.gets.load(Ordering::Relaxed);
+        let probes = self.probes.load(Ordering::Relaxed);
+        if gets == 0 {
+            0.0
+        } else {
+            probes as f64 / gets as f64
+        }
+    }
+
+    fn reset(&self) {
+        self.gets.store(0, Ordering::Relaxed);
+        self.probes.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Everything reads and writes share.
+struct State {
     memtable: MemTable,
-    wal: WAL,
-    sstables: Vec<SSTableReader>,
+    /// Full memtables waiting to be flushed, newest first.
+    immutable: Vec<Arc<ImmutableMemTable>>,
+    /// Replaced as a whole whenever tables come or go, so that readers can
+    /// keep using the list they started with.
+    sstables: Arc<Vec<Arc<SSTableReader>>>,
+    /// Taken by readers along with `sstables`.
+    read_epoch: Arc<ReadEpoch>,
+    /// Tables being merged by a running compaction.
+    compacting: HashSet<PathBuf>,
+    /// First error a background job ran into since
+    /// [`wait_for_background_jobs`](Database::wait_for_background_jobs) last
+    /// reported one.
+    background_error: Option<(io::ErrorKind, String)>,
     compaction_policy: CompactionPolicy,
     last_compaction_time: SystemTime,
+    /// Where the next leveled compaction of each level picks up.
+    compact_cursors: HashMap<u32, String>,
+    last_sequence: u64,
+    flushed_sequence: u64,
+    /// Oldest WAL segment holding writes that aren't in a table yet.
+    log_number: u64,
+}
+
+/// A memtable that stopped taking writes and waits to be flushed.
+struct ImmutableMemTable {
+    memtable: MemTable,
+    first_sequence: u64,
+    last_sequence: u64,
+    /// WAL segment the writes after this memtable's start in.
+    log_number: u64,
+}
+
+/// Held by readers for as long as they use the table list they took, so
+/// that the blob files it points into outlive them.
+///
+/// Each epoch keeps the next one alive, so the blob files retired with an
+/// epoch are deleted once no reader holds it or any older one.
+struct ReadEpoch {
+    blob_store: Arc<BlobStore>,
+    /// Set when the epoch ends: the one after it, and the blob files no
+    /// later table list points into.
+    retired: OnceLock<(Arc<ReadEpoch>, HashSet<u64>)>,
+}
+
+impl ReadEpoch {
+    fn new(blob_store: Arc<BlobStore>) -> Self {
+        Self {
+            blob_store,
+            retired: OnceLock::new(),
+        }
+    }
+}
+
+impl Drop for ReadEpoch {
+    fn drop(&mut self) {
+        if let Some((_, dead)) = self.retired.get() {
+            // A file left behind is picked up again by the next collection
+            for &number in dead {
+                let _ = self.blob_store.remove_file(number);
+            }
+        }
+    }
+}
+
+/// A write waiting to be committed by the current group leader.
+struct PendingWrite {
+    ops: Vec<(String, Option<String>)>,
+    sync: Option<bool>,
+}
+
+/// Writes are numbered as they are queued. Those from `committed` up to
+/// `next_ticket` are waiting in `pending`.
+#[derive(Default)]
+struct CommitQueue {
+    pending: Vec<PendingWrite>,
+    next_ticket: u64,
+    committed: u64,
+    /// Whether a writer is committing a group right now.
+    leader: bool,
+    /// Errors for committed writes whose writers haven't woken up yet.
+    failures: HashMap<u64, (io::ErrorKind, String)>,
 }
 
 const MEMTABLE_THRESHOLD: usize = 4 * 1024 * 1024; // 4MB
 
+/// Writes stall while this many full memtables wait to be flushed.
+const MAX_IMMUTABLE_MEMTABLES: usize = 2;
+
 impl Database {
+    /// Opens the database at `path` with default options, creating it if it
+    /// does not exist.
     pub fn new(path: impl AsRef<Path>) -> Database {
-        let path = path.as_ref().to_path_buf();
+        Self::open(path, Options::default()).expect("Unable to create database")
+    }
 
-        if !path.exists() {
-            fs::create_dir_all(&path).expect("Unable to create database directory");
-        }
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Database> {
+        Self::open(path, Options::default())
+    }
 
-        let wal_path = path.join("wal.log");
-        let wal = WAL::new(&wal_path).expect("Unable to create WAL");
+    /// Loads the database at `path`, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>, options: Options) -> io::Result<Database> {
+        let threads = options.background_threads;
+        let inner = Arc::new(Inner::open(path.as_ref().to_path_buf(), options)?);
 
-        Database {
-            path,
-            memtable: MemTable::new(),
-            wal,
-            sstables: Vec::new(),
-            compaction_policy: CompactionPolicy::Disabled,
-            last_compaction_time: SystemTime::now(),
-        }
+        let pool = inner.jobs.clone().map(|jobs| {
+            let db = inner.clone();
+            let interval = db.options.compaction_check_interval;
+            BackgroundPool::start(jobs, threads, interval, move |job| db.run_job(job))
+        });
+
+        Ok(Database {
+            path: inner.path.clone(),
+            inner,
+            _pool: pool,
+        })
     }
 
-    pub fn load(path: impl AsRef<Path>) -> io::Result<Database> {
-        let path = path.as_ref().to_path_buf();
+    pub fn set_compaction_policy(&self, policy: CompactionPolicy) {
+        self.inner.state.lock().unwrap().compaction_policy = policy;
+        self.inner.schedule(Job::Compaction);
+    }
 
-        if !path.exists() {
-            return Ok(Database::new(&path));
+    pub fn set(&self, key: String, value: String) {
+        self.set_with_options(key, value, &WriteOptions::default());
+    }
+
+    pub fn set_with_options(&self, key: String, value: String, options: &WriteOptions) {
+        self.inner
+            .write(vec![(key, Some(value))], options)
+            .expect("Failed to write to WAL");
+        self.inner
+            .make_room_for_writes()
+            .expect("Failed to flush memtable");
+
+        self.inner
+            .trigger_compaction()
+            .expect("Auto-compaction failed");
+    }
+
+    pub fn batch_set(&self, entries: Vec<(String, String)>) {
+        self.batch_set_with_options(entries, &WriteOptions::default());
+    }
+
+    pub fn batch_set_with_options(&self, entries: Vec<(String, String)>, options: &WriteOptions) {
+        let ops = entries.into_iter().map(|(k, v)| (k, Some(v))).collect();
+        self.inner
+            .write(ops, options)
+            .expect("Failed to write to WAL");
+        self.inner
+            .make_room_for_writes()
+            .expect("Failed to flush memtable");
+
+        self.inner
+            .trigger_compaction()
+            .expect("Auto-compaction failed");
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let start = Instant::now();
+        let value = self.inner.get(key);
+        self.inner.record_read_latency(start);
+        value.expect("Failed to read SSTable")
+    }
+
+    pub fn del(&self, key: &str) {
+        self.del_with_options(key, &WriteOptions::default());
+    }
+
+    pub fn del_with_options(&self, key: &str, options: &WriteOptions) {
+        self.inner
+            .write(vec![(key.to_string(), None)], options)
+            .expect("Failed to write to WAL");
+        self.inner
+            .make_room_for_writes()
+            .expect("Failed to flush memtable");
+
+        self.inner
+            .trigger_compaction()
+            .expect("Auto-compaction failed");
+    }
+
+    pub fn get_by_prefix(&self, prefix: &str) -> Vec<String> {
+        let start = Instant::now();
+        let values = self.inner.get_by_prefix(prefix);
+        self.inner.record_read_latency(start);
+        values.expect("Failed to read SSTable")
+    }
+
+    /// Forces every logged write to disk, whatever the WAL sync mode.
+    pub fn sync_wal(&self) -> io::Result<()> {
+        self.inner.wal.lock().unwrap().sync()
+    }
+
+    /// Sync counters of the WAL.
+    pub fn wal_stats(&self) -> WalStats {
+        self.inner.wal.lock().unwrap().stats()
+    }
+
+    /// What WAL replay found when the database was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.inner.recovery_report
+    }
+
+    /// Sequence number of the most recent write.
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.state.lock().unwrap().last_sequence
+    }
+
+    /// Level, size and key range of every live SSTable, in lookup order.
+    pub fn tables(&self) -> Vec<TableInfo> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .sstables
+            .iter()
+            .map(|t| TableInfo {
+                name: t.path().file_name().unwrap().to_string_lossy().into_owned(),
+                level: t.level(),
+                file_size: t.file_size(),
+                properties: t.properties().cloned(),
+            })
+            .collect()
+    }
+
+    /// Properties of every live SSTable that records them, newest first.
+    pub fn table_properties(&self) -> Vec<TableProperties> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .sstables
+            .iter()
+            .filter_map(|t| t.properties().cloned())
+            .collect()
+    }
+
+    /// Hit/miss counters and usage of the shared block cache.
+    pub fn block_cache_stats(&self) -> CacheStats {
+        self.inner.block_cache.stats()
+    }
+
+    /// Writes the memtable, and any full ones still waiting for a background
+    /// flush, to SSTables.
+    pub fn flush(&self) {
+        let mut wal = self.inner.wal.lock().unwrap();
+        self.inner
+            .flush_memtable(&mut wal)
+            .expect("Failed to flush");
+    }
+
+    /// Blocks until the background threads have no flush or compaction left
+    /// to run, and returns the first error one of them ran into since the
+    /// last call.
+    ///
+    /// Failed jobs don't fail writes: flushes and compactions are retried
+    /// later, and this is where their errors are reported.
+    pub fn wait_for_background_jobs(&self) -> io::Result<()> {
+        if let Some(jobs) = &self.inner.jobs {
+            jobs.wait_idle();
         }
+        self.inner.take_background_error()
+    }
 
-        let wal_path = path.join("wal.log");
-        let wal = WAL::new(&wal_path)?;
-        let mut memtable = MemTable::new();
+    /// Adds SSTables written by [`SstFileWriter`](crate::SstFileWriter) to
+    /// the database.
+    ///
+    /// Every file is checked to hold strictly increasing keys and no two
+    /// files may overlap. The files are copied into the database and become
+    /// visible together, ahead of every existing table. If the memtable holds
+    /// keys in their range it is flushed first, so the ingested entries
+    /// shadow those too.
+    pub fn ingest_external_files(&self, paths: &[impl AsRef<Path>]) -> io::Result<()> {
+        self.inner.ingest_external_files(paths)
+    }
+
+    /// Writes a copy of the database into `dir`, which must not exist yet.
+    ///
+    /// The memtable is flushed first, so the copy holds every write made so
+    /// far. Values kept in blob files are copied into its tables, so it can
+    /// be opened as a database of its own, or serve as the starting point of
+    /// [`restore_to`](Self::restore_to).
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        self.inner.checkpoint(dir.as_ref())
+    }
+
+    /// Rolls the database back to `target`.
+    ///
+    /// The tables are replaced by those of the checkpoint in `backup`, and
+    /// the writes logged after the checkpoint are replayed on top of them up
+    /// to `target`, from the WAL archive and then the live WAL. Everything
+    /// else, including the memtable, is discarded.
+    ///
+    /// Fails without touching the database if `target` lies before the
+    /// checkpoint, or if writes between the checkpoint and `target` are
+    /// missing from the logs, as they are once archived segments expire or
+    /// after files were ingested past the checkpoint.
+    ///
+    /// **Writes past the target are cut from the WAL archive**, since new
+    /// writes will reuse their sequence numbers. Once the restored database
+    /// is durable, every archived segment holding such writes is copied whole
+    /// into a new `generation_<n>` directory of the archive before it is cut.
+    /// Restores never read those directories: rolling forward again means
+    /// moving the copies back over the cut segments before anything else is
+    /// written.
+    pub fn restore_to(&self, backup: impl AsRef<Path>, target: RestoreTarget) -> io::Result<()> {
+        self.inner.restore_to(backup.as_ref(), target)
+    }
+
+    /// Merges every table into a single sorted run in the deepest level,
+    /// dropping tombstones and shadowed versions.
+    ///
+    /// Waits for running compactions to finish first.
+    pub fn compact(&self) -> io::Result<()> {
+        self.inner.compact()
+    }
+
+    /// Average number of tables gets have searched since the last full
+    /// compaction. Gets answered by the memtable count as searching none.
+    pub fn read_amplification(&self) -> f64 {
+        self.inner.read_stats.amplification()
+    }
+
+    /// Merges the tables holding keys in `[start, end]` into the deepest
+    /// level, dropping tombstones and shadowed versions in them.
+    ///
+    /// Tables are merged whole, so keys outside the range that share a table
+    /// with keys in it are rewritten too, along with any tables those
+    /// overlap. Tables that hold no key in the range are left alone. The
+    /// memtable is flushed first, and running compactions are waited for.
+    pub fn compact_range(&self, start: &str, end: &str) -> io::Result<()> {
+        self.inner.compact_range(start, end)
+    }
+
+    /// Reclaims space held by overwritten and deleted blob values.
+    ///
+    /// Blob files that no live table points into are deleted. Files whose
+    /// live bytes fall below `blob_gc_ratio` of their size have their
+    /// remaining values copied to the active blob file, the tables pointing
+    /// at them are rewritten, and the files are then deleted.
+    pub fn collect_blob_garbage(&self) -> io::Result<()> {
+        self.inner.collect_blob_garbage()
+    }
+}
 
-        if wal_path.exists() {
-            let iter = WALIterator::new(&wal_path)?;
-            for entry in iter {
-                let (key, val) = entry?;
-                if let Some(v) = val {
-                    memtable.set(key, v);
-                } else {
-                    memtable.del(key);
+impl Inner {
+    fn open(path: PathBuf, options: Options) -> io::Result<Inner> {
+        let mut db = Self::create(path, options)?;
+        let mut wal = db.wal.lock().unwrap();
+        let mut state = db.state.lock().unwrap();
+
+        match Manifest::load(&db.path)? {
+            Some(manifest) => {
+                state.log_number = manifest.log_number;
+                let mut live = HashSet::new();
+                for entry in manifest.tables {
+                    let mut table = db.open_sstable(&db.path.join(&entry.name))?;
+                    if entry.global_sequence > 0 {
+                        table.set_global_sequence(entry.global_sequence);
+                    }
+                    table.set_level(entry.level);
+                    state.tables_mut().push(Arc::new(table));
+                    live.insert(entry.name);
+                }
+
+                // Drop tables left behind by a flush, compaction or
+                // ingestion that never made it into the manifest
+                for path in list_table_files(&db.path)? {
+                    let name = path
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or_default();
+                    if !live.contains(name) {
+                        fs::remove_file(&path)?;
+                    }
                 }
             }
+            None => {
+                // Databases from before the manifest: newest tables first,
+                // ordered by sequence where the tables record it
+                for path in list_table_files(&db.path)? {
+                    if path.extension().is_some_and(|ext| ext == "sst") {
+                        let table = db.open_sstable(&path)?;
+                        state.tables_mut().push(Arc::new(table));
+                    }
+                }
+                state.tables_mut().sort_by(|a, b| {
+                    (table_sequence(b), b.path()).cmp(&(table_sequence(a), a.path()))
+                });
+                db.write_manifest(&state)?;
+            }
         }
 
-        let mut sstables = Vec::new();
-        let entries = fs::read_dir(&path)?;
-        let mut sstable_files: Vec<PathBuf> = entries
-            .filter_map(|e| e.ok())
-            .map(|e| e.path())
-            .filter(|p| p.extension().map_or(false, |ext| ext == "sst"))
-            .collect();
+        // Everything up to the newest table's sequence is already on disk
+        state.flushed_sequence = state
+            .sstables
+            .iter()
+            .map(|t| table_sequence(t))
+            .max()
+            .unwrap_or(0);
+        state.last_sequence = state.flushed_sequence;
+
+        // Replay every segment the tables don't cover yet, oldest first
+        let mut report = RecoveryReport::default();
+        let mut legacy = false;
+        db.retire_segments(state.log_number)?;
+        for (_, path) in wal::segments(&db.path)? {
+            let mut iter = WALIterator::with_recovery_mode(&path, db.options.wal_recovery_mode)?;
+            for entry in &mut iter {
+                let record = entry?;
+                match record.value {
+                    Some(value) => state.memtable.set(record.key, value),
+                    None => state.memtable.del(record.key),
+                }
+                // Records from before sequence numbers were logged follow on
+                // from the previous one
+                state.last_sequence = match record.sequence {
+                    0 => state.last_sequence + 1,
+                    sequence => state.last_sequence.max(sequence),
+                };
+            }
+            report.add(iter.report());
 
-        sstable_files.sort();
-        sstable_files.reverse();
+            if iter.is_legacy() {
+                legacy = true;
+            } else if iter.valid_len() < fs::metadata(&path)?.len() {
+                wal::truncate_segment(&path, iter.valid_len())?;
+            }
+        }
 
-        for sst_path in sstable_files {
-            sstables.push(SSTableReader::new(sst_path)?);
+        drop(state);
+        if legacy {
+            // Move the records of an unframed log into a table, so that it
+            // isn't needed anymore
+            db.flush_memtable(&mut wal)?;
+            let legacy_path = wal::segment_path(&db.path, 0);
+            if legacy_path.exists() {
+                fs::remove_file(legacy_path)?;
+            }
         }
 
-        Ok(Database {
+        drop(wal);
+        db.recovery_report = report;
+        Ok(db)
+    }
+
+    fn create(path: PathBuf, options: Options) -> io::Result<Inner> {
+        if !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
+
+        let wal = WAL::with_sync_mode(&path, options.wal_sync_mode)?;
+        let blob_store = Arc::new(BlobStore::open(&path)?);
+
+        let jobs = (options.background_threads > 0).then(|| Arc::new(JobQueue::default()));
+
+        Ok(Inner {
             path,
-            memtable,
-            wal,
-            sstables,
-            compaction_policy: CompactionPolicy::Disabled,
-            last_compaction_time: SystemTime::now(),
+            wal: Mutex::new(wal),
+            flush_lock: Mutex::new(()),
+            state: Mutex::new(State {
+                memtable: MemTable::new(),
+                immutable: Vec::new(),
+                sstables: Arc::new(Vec::new()),
+                read_epoch: Arc::new(ReadEpoch::new(blob_store.clone())),
+                compacting: HashSet::new(),
+                background_error: None,
+                compaction_policy: CompactionPolicy::Disabled,
+                last_compaction_time: SystemTime::now(),
+                compact_cursors: HashMap::new(),
+                last_sequence: 0,
+                flushed_sequence: 0,
+                log_number: 0,
+            }),
+            job_done: Condvar::new(),
+            commits: Mutex::new(CommitQueue::default()),
+            committed: Condvar::new(),
+            jobs,
+            block_cache: Arc::new(BlockCache::new(options.block_cache_capacity)),
+            blob_store,
+            read_stats: ReadStats::default(),
+            options,
+            recovery_report: RecoveryReport::default(),
         })
     }
 
-    pub fn set_compaction_policy(&mut self, policy: CompactionPolicy) {
-        self.compaction_policy = policy;
+    /// Queues `job` for the background threads, if there are any.
+    fn schedule(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            jobs.schedule(job);
+        }
     }
 
-    fn try_trigger_compaction(&mut self) -> io::Result<()> {
-        let duration = match self.compaction_policy {
-            CompactionPolicy::Periodic(d) => d,
-            CompactionPolicy::Disabled => return Ok(()),
+    fn run_job(&self, job: Job) {
+        let result = match job {
+            Job::Flush => self.flush_immutable_memtables(),
+            Job::Compaction => self.compact_by_policy(),
         };
 
-        if self
-            .last_compaction_time
-            .elapsed()
-            .map_or(false, |e| e >= duration)
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = result
+            && state.background_error.is_none()
         {
-            self.compact()?;
+            state.background_error = Some((e.kind(), e.to_string()));
         }
-        Ok(())
+        drop(state);
+        self.job_done.notify_all();
     }
 
-    pub fn set(&mut self, key: String, value: String) {
-        self.wal.set(&key, &value).expect("Failed to write to WAL");
-        self.memtable.set(key, value);
+    fn take_background_error(&self) -> io::Result<()> {
+        match self.state.lock().unwrap().background_error.take() {
+            Some((kind, msg)) => Err(io::Error::new(kind, msg)),
+            None => Ok(()),
+        }
+    }
 
-        if self.memtable.size_bytes() >= MEMTABLE_THRESHOLD {
-            self.flush_memtable().expect("Failed to flush memtable");
+    /// Runs the compaction policy after a write, or has a background thread
+    /// run it.
+    ///
+    /// The background threads already run it whenever tables are added, so
+    /// writes only need to wake them up for policies that go by the clock.
+    fn trigger_compaction(&self) -> io::Result<()> {
+        if self.jobs.is_none() {
+            return self.compact_by_policy();
         }
 
-        self.try_trigger_compaction()
-            .expect("Auto-compaction failed");
+        let state = self.state.lock().unwrap();
+        let due = match state.compaction_policy {
+            CompactionPolicy::Periodic(duration) => state
+                .last_compaction_time
+                .elapsed()
+                .is_ok_and(|e| e >= duration),
+            CompactionPolicy::Fifo { max_age, .. } => max_age.is_some(),
+            CompactionPolicy::Triggered(triggers) => triggers.interval.is_some_and(|interval| {
+                state
+                    .last_compaction_time
+                    .elapsed()
+                    .is_ok_and(|e| e >= interval)
+            }),
+            _ => false,
+        };
+        drop(state);
+        if due {
+            self.schedule(Job::Compaction);
+        }
+        Ok(())
     }
 
-    pub fn batch_set(&mut self, entries: Vec<(String, String)>) {
-        self.wal
-            .batch_set(&entries)
-            .expect("Failed to write to WAL");
-        for (key, value) in entries {
-            self.memtable.set(key, value);
+    fn compact_by_policy(&self) -> io::Result<()> {
+        let policy = self.state.lock().unwrap().compaction_policy;
+        match policy {
+            CompactionPolicy::Periodic(duration) => {
+                let due = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .last_compaction_time
+                    .elapsed()
+                    .is_ok_and(|e| e >= duration);
+                if due {
+                    self.compact()?;
+                }
+            }
+            CompactionPolicy::Leveled {
+                l0_trigger,
+                level_multiplier,
+                base_level_size,
+            } => self.compact_while(|state| {
+                compaction::pick_leveled(
+                    &state.sstables,
+                    l0_trigger,
+                    level_multiplier,
+                    base_level_size,
+                    &state.compact_cursors,
+                )
+            })?,
+            CompactionPolicy::SizeTiered {
+                min_threshold,
+                max_threshold,
+                bucket_ratio,
+            } => self.compact_while(|state| {
+                compaction::pick_size_tiered(
+                    &state.sstables,
+                    min_threshold,
+                    max_threshold,
+                    bucket_ratio,
+                )
+            })?,
+            CompactionPolicy::TimeWindow {
+                window,
+                min_threshold,
+            } => self.compact_while(|state| {
+                compaction::pick_time_window(&state.sstables, window, min_threshold)
+            })?,
+            CompactionPolicy::Fifo {
+                max_total_size,
+                max_age,
+            } => {
+                let mut state = self.state.lock().unwrap();
+                let dropped = compaction::pick_fifo(&state.sstables, max_total_size, max_age);
+                if !dropped.is_empty() && !state.is_busy(&dropped) {
+                    self.drop_tables(&mut state, &dropped)?;
+                    drop(state);
+                    self.collect_blob_garbage()?;
+                }
+            }
+            CompactionPolicy::Triggered(triggers) => {
+                let state = self.state.lock().unwrap();
+                let due = compaction::full_compaction_due(
+                    &state.sstables,
+                    &triggers,
+                    self.read_stats.amplification(),
+                    state.last_compaction_time,
+                );
+                drop(state);
+                if due {
+                    self.compact()?;
+                } else if let Some(max_ratio) = triggers.max_tombstone_ratio {
+                    self.compact_while(|state| {
+                        let max_level = state.sstables.iter().map(|t| t.level()).max();
+                        compaction::pick_tombstone_dense(
+                            &state.sstables,
+                            max_ratio,
+                            max_level.unwrap_or(0).max(1),
+                        )
+                    })?;
+                }
+            }
+            CompactionPolicy::Disabled => {}
         }
+        Ok(())
+    }
 
-        if self.memtable.size_bytes() >= MEMTABLE_THRESHOLD {
-            self.flush_memtable().expect("Failed to flush memtable");
+    /// Runs the jobs `pick` comes up with until it has none left, or picks
+    /// tables another compaction is still merging.
+    fn compact_while(&self, pick: impl Fn(&State) -> Option<CompactionJob>) -> io::Result<()> {
+        loop {
+            let state = self.state.lock().unwrap();
+            match pick(&state) {
+                Some(job) if !state.is_busy(&job.inputs) => self.run_compaction(state, job)?,
+                _ => return Ok(()),
+            }
         }
-
-        self.try_trigger_compaction()
-            .expect("Auto-compaction failed");
     }
 
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        if let Some(val_opt) = self.memtable.get(key) {
-            return val_opt;
+    /// Feeds the time since `start` to an auto-tuned rate limiter.
+    fn record_read_latency(&self, start: Instant) {
+        if let Some(limiter) = &self.options.rate_limiter
+            && limiter.is_auto_tuned()
+        {
+            limiter.record_latency(start.elapsed());
         }
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        self.read_stats.gets.fetch_add(1, Ordering::Relaxed);
+        let (tables, _epoch) = {
+            let state = self.state.lock().unwrap();
+            if let Some(val_opt) = state.memtable.get(key) {
+                return Ok(val_opt);
+            }
+            for frozen in &state.immutable {
+                if let Some(val_opt) = frozen.memtable.get(key) {
+                    return Ok(val_opt);
+                }
+            }
+            state.snapshot()
+        };
+
+        for sstable in tables.iter() {
+            if !sstable.may_contain(key) {
+                continue;
+            }
 
-        for sstable in &mut self.sstables {
-            match sstable.get(key) {
-                Ok(SearchResult::Found(val)) => return Some(val),
-                Ok(SearchResult::Deleted) => return None,
-                Ok(SearchResult::NotFound) => continue,
-                Err(_) => continue, // Should handle error appropriately, but for now continue
+            self.read_stats.probes.fetch_add(1, Ordering::Relaxed);
+            // An older table may hold a value this one overwrote, so a table
+            // that can't be read fails the get
+            match sstable.get(key)? {
+                SearchResult::Found(val) => return Ok(Some(val)),
+                SearchResult::Deleted => return Ok(None),
+                SearchResult::NotFound => continue,
             }
         }
 
-        None
+        Ok(None)
     }
 
-    pub fn del(&mut self, key: &str) {
-        self.wal.del(key).expect("Failed to write to WAL");
-        self.memtable.del(key.to_string());
-
-        if self.memtable.size_bytes() >= MEMTABLE_THRESHOLD {
-            self.flush_memtable().expect("Failed to flush memtable");
+    /// Logs and applies `ops` as part of a commit group.
+    ///
+    /// The writer queues its records, then either waits for the current
+    /// leader to commit them or, if there is none, becomes the leader and
+    /// commits everything queued so far, its own write included.
+    ///
+    /// Every writer only hears whether its records were logged; making room
+    /// for more writes is left to
+    /// [`make_room_for_writes`](Self::make_room_for_writes).
+    fn write(&self, ops: Vec<(String, Option<String>)>, options: &WriteOptions) -> io::Result<()> {
+        let mut queue = self.commits.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push(PendingWrite {
+            ops,
+            sync: options.sync,
+        });
+
+        while queue.leader && queue.committed <= ticket {
+            queue = self.committed.wait(queue).unwrap();
+        }
+        if queue.committed > ticket {
+            return match queue.failures.remove(&ticket) {
+                Some((kind, msg)) => Err(io::Error::new(kind, msg)),
+                None => Ok(()),
+            };
         }
 
-        self.try_trigger_compaction()
-            .expect("Auto-compaction failed");
+        queue.leader = true;
+        let group = std::mem::take(&mut queue.pending);
+        let group_start = queue.committed;
+        let group_end = queue.next_ticket;
+        drop(queue);
+
+        let result = self.commit_group(group);
+
+        let mut queue = self.commits.lock().unwrap();
+        if let Err(e) = &result {
+            for t in (group_start..group_end).filter(|&t| t != ticket) {
+                queue.failures.insert(t, (e.kind(), e.to_string()));
+            }
+        }
+        queue.committed = group_end;
+        queue.leader = false;
+        drop(queue);
+        self.committed.notify_all();
+        result
     }
 
-    pub fn get_by_prefix(&mut self, prefix: &str) -> Vec<String> {
-        let mut map = std::collections::BTreeMap::new();
+    /// Appends the records of every write in `group` to the WAL at once and
+    /// applies them to the memtable in queue order.
+    fn commit_group(&self, group: Vec<PendingWrite>) -> io::Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+
+        // One write that asks for a sync gets it for the whole group; the
+        // sync mode decides unless every write opted out
+        let sync = if group.iter().any(|w| w.sync == Some(true)) {
+            Some(true)
+        } else if group.iter().all(|w| w.sync == Some(false)) {
+            Some(false)
+        } else {
+            None
+        };
 
-        // 1. Scan SSTables (oldest to newest, so newer overwrites older)
-        for sstable in self.sstables.iter_mut().rev() {
-            let start = prefix;
-            let end = format!("{}{}", prefix, '\u{10FFFF}'); // Max char
+        // Only writers holding the WAL lock move the sequence on
+        let mut sequence = self.state.lock().unwrap().last_sequence;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let mut records = Vec::new();
+        for write in group {
+            for (key, value) in write.ops {
+                sequence += 1;
+                records.push(WalRecord {
+                    sequence,
+                    timestamp,
+                    key,
+                    value,
+                });
+            }
+        }
+        #[cfg(feature = "failpoints")]
+        if let Some(delay) = self.options.commit_delay {
+            std::thread::sleep(delay);
+        }
+        wal.append(&records, &WriteOptions { sync })?;
+
+        let mut state = self.state.lock().unwrap();
+        for record in records {
+            match record.value {
+                Some(value) => state.memtable.set(record.key, value),
+                None => state.memtable.del(record.key),
+            }
+        }
+        state.last_sequence = sequence;
+        Ok(())
+    }
 
-            if let Ok(entries) = sstable.scan(start, &end) {
-                for (k, v) in entries {
-                    map.insert(k, Some(v));
+    /// Flushes the memtable once it is full, or hands it to the background
+    /// threads.
+    fn make_room_for_writes(&self) -> io::Result<()> {
+        loop {
+            let mut wal = self.wal.lock().unwrap();
+            let mut state = self.state.lock().unwrap();
+            if state.memtable.size_bytes() < MEMTABLE_THRESHOLD {
+                return Ok(());
+            }
+            if self.jobs.is_none() {
+                drop(state);
+                return self.flush_memtable(&mut wal);
+            }
+            if state.immutable.len() < MAX_IMMUTABLE_MEMTABLES {
+                self.freeze_memtable(&mut state, &mut wal)?;
+                self.schedule(Job::Flush);
+                return Ok(());
+            }
+
+            // Stall while the background threads fall behind on flushes,
+            // without the WAL, which a background compaction may need to flush
+            // the memtable before it gets to them
+            drop(wal);
+            while state.immutable.len() >= MAX_IMMUTABLE_MEMTABLES {
+                // Nothing schedules another flush while writes are stalled,
+                // so one that failed is retried here
+                if state.background_error.is_some() {
+                    drop(state);
+                    self.flush_immutable_memtables()?;
+                    break;
                 }
+                state = self.job_done.wait(state).unwrap();
             }
         }
+    }
+
+    fn get_by_prefix(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut map = std::collections::BTreeMap::new();
+        let start = prefix;
+        let end = format!("{}{}", prefix, '\u{10FFFF}'); // Max char
+
+        // Copy the memtables' entries out, so tables are scanned unlocked
+        let ((tables, _epoch), memtables) = {
+            let state = self.state.lock().unwrap();
+            let mut memtables = Vec::new();
+            for memtable in state
+                .immutable
+                .iter()
+                .rev()
+                .map(|frozen| &frozen.memtable)
+                .chain([&state.memtable])
+            {
+                for (k, v) in memtable.iter() {
+                    if k.starts_with(prefix) {
+                        memtables.push((k.clone(), v.clone()));
+                    }
+                }
+            }
+            (state.snapshot(), memtables)
+        };
+
+        // 1. Scan SSTables (oldest to newest, so newer overwrites older)
+        for sstable in tables.iter().rev() {
+            if !sstable.overlaps(start, &end) {
+                continue;
+            }
+            if let Some(extractor) = &self.options.prefix_extractor
+                && !sstable.may_contain_prefix(extractor.as_ref(), prefix)
+            {
+                continue;
+            }
 
-        // 2. Scan MemTable
-        for (k, v) in self.memtable.iter() {
-            if k.starts_with(prefix) {
-                map.insert(k.clone(), v.clone());
+            for entry in sstable.scan(start, &end)? {
+                let (k, v) = entry?;
+                map.insert(k, Some(v));
             }
         }
 
+        // 2. Apply the memtables, oldest first
+        map.extend(memtables);
+
         // 3. Collect results (filter out tombstones)
-        map.into_values().flatten().collect()
+        Ok(map.into_values().flatten().collect())
     }
 
-    pub fn flush(&mut self) {
-        self.flush_memtable().expect("Failed to flush");
+    /// Writes the memtable and every immutable one to tables.
+    ///
+    /// Taking `wal` keeps writes out until the memtables are flushed.
+    fn flush_memtable(&self, wal: &mut WAL) -> io::Result<()> {
+        self.freeze_memtable(&mut self.state.lock().unwrap(), wal)?;
+        self.flush_immutable_memtables()
     }
 
-    fn flush_memtable(&mut self) -> io::Result<()> {
-        if self.memtable.len() == 0 {
+    /// Turns the memtable into an immutable one, to be flushed, and starts a
+    /// new WAL segment for the writes after it.
+    fn freeze_memtable(&self, state: &mut State, wal: &mut WAL) -> io::Result<()> {
+        if state.memtable.is_empty() {
             return Ok(());
         }
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros();
+        // Later writes go to a new segment, so the current ones can be
+        // deleted as a whole once the table is recorded
+        wal.rotate()?;
+
+        let first_sequence = state
+            .immutable
+            .first()
+            .map_or(state.flushed_sequence, |frozen| frozen.last_sequence)
+            + 1;
+        let frozen = ImmutableMemTable {
+            memtable: std::mem::take(&mut state.memtable),
+            first_sequence,
+            last_sequence: state.last_sequence,
+            log_number: wal.number(),
+        };
+        state.immutable.insert(0, Arc::new(frozen));
+        Ok(())
+    }
 
-        let sst_name = format!("sstable_{}.sst", timestamp);
-        let sst_path = self.path.join(sst_name);
+    /// Flushes immutable memtables, oldest first, until none are left.
+    fn flush_immutable_memtables(&self) -> io::Result<()> {
+        let mut flushed = false;
+        loop {
+            let _flushing = self.flush_lock.lock().unwrap();
+            let Some(frozen) = self.state.lock().unwrap().immutable.last().cloned() else {
+                break;
+            };
 
-        let mut builder = SSTableBuilder::new(&sst_path)?;
+            let tables = self.write_memtable(&frozen)?;
+            let mut state = self.state.lock().unwrap();
+            self.install_flush(&mut state, tables)?;
+            flushed = true;
+        }
 
-        for (key, val_opt) in self.memtable.iter() {
+        if flushed {
+            self.schedule(Job::Compaction);
+        }
+        Ok(())
+    }
+
+    fn write_memtable(&self, frozen: &ImmutableMemTable) -> io::Result<Vec<SSTableReader>> {
+        let mut writer = TableWriter::new(self, "sstable");
+        writer.set_sequence_range(frozen.first_sequence, frozen.last_sequence);
+
+        for (key, val_opt) in frozen.memtable.iter() {
             if let Some(val) = val_opt {
-                builder.add(key, val)?;
+                writer.builder()?.add(key, val)?;
             } else {
-                builder.delete(key)?;
+                writer.builder()?.delete(key)?;
             }
         }
 
-        builder.finish()?;
+        let new_tables = writer.finish()?;
+        new_tables
+            .iter()
+            .map(|path| self.open_sstable(path))
+            .collect()
+    }
+
+    /// Swaps the tables written from the oldest immutable memtable in for it.
+    fn install_flush(&self, state: &mut State, tables: Vec<SSTableReader>) -> io::Result<()> {
+        let frozen = state.immutable.pop().expect("No memtable was flushed");
 
-        // Add to list (at the front, as it's newest)
-        self.sstables.insert(0, SSTableReader::new(sst_path)?);
+        // Add to list (at the front, as they're newest)
+        state
+            .tables_mut()
+            .splice(0..0, tables.into_iter().map(Arc::new));
 
-        // Clear MemTable and WAL
-        self.memtable.clear();
-        self.wal.clear()?;
+        state.log_number = frozen.log_number;
+        state.flushed_sequence = state.flushed_sequence.max(frozen.last_sequence);
+        self.write_manifest(state)?;
+        self.retire_segments(state.log_number)?;
 
+        self.job_done.notify_all();
         Ok(())
     }
 
-    pub fn compact(&mut self) -> io::Result<()> {
-        self.flush_memtable()?;
+    /// Archives or deletes the WAL segments older than `number`, whose
+    /// writes the tables hold.
+    fn retire_segments(&self, number: u64) -> io::Result<()> {
+        let archive = self.options.wal_archive_dir.as_deref();
+        wal::retire_segments_before(&self.path, number, archive)?;
 
-        if self.sstables.is_empty() {
-            return Ok(());
+        if let (Some(archive), Some(ttl)) = (archive, self.options.wal_archive_ttl)
+            && archive.exists()
+        {
+            wal::expire_archive(archive, ttl)?;
         }
+        Ok(())
+    }
 
-        let old_sstables = std::mem::take(&mut self.sstables);
+    fn open_sstable(&self, path: &Path) -> io::Result<SSTableReader> {
+        let mut table = if self.options.mmap_reads {
+            SSTableReader::with_mmap(path)?
+        } else {
+            SSTableReader::with_cache(path, self.block_cache.clone())?
+        };
+        table.set_blob_store(self.blob_store.clone());
+        Ok(table)
+    }
 
-        let mut iters: Vec<_> = old_sstables
-            .into_iter()
-            .map(|sst| sst.into_iter().peekable())
-            .collect();
+    fn new_table_builder(&self, path: &Path) -> io::Result<SSTableBuilder> {
+        let mut builder = self.new_inline_table_builder(path)?;
+        if let Some(threshold) = self.options.blob_threshold {
+            builder.set_blob_store(self.blob_store.clone(), threshold);
+        }
+        Ok(builder)
+    }
+
+    /// A table builder that keeps every value inline, for tables that must
+    /// not point into the database's blob files.
+    fn new_inline_table_builder(&self, path: &Path) -> io::Result<SSTableBuilder> {
+        let mut builder = SSTableBuilder::new(path)?;
+        if let Some(size) = self.options.index_partition_size {
+            builder.set_index_partition_size(size);
+        }
+        if let Some(extractor) = &self.options.prefix_extractor {
+            builder.set_prefix_extractor(extractor.clone());
+        }
+        builder.set_block_hash_index(self.options.block_hash_index);
+        if let Some(limiter) = &self.options.rate_limiter
+            && background::is_background_thread()
+        {
+            builder.set_rate_limiter(limiter.clone());
+        }
+        Ok(builder)
+    }
+
+    fn ingest_external_files(&self, paths: &[impl AsRef<Path>]) -> io::Result<()> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            let (smallest, largest) = validate_external_file(path)?;
+            files.push((smallest, largest, path));
+        }
+
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        for pair in files.windows(2) {
+            if pair[0].1 >= pair[1].0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} overlaps {}", pair[0].2.display(), pair[1].2.display()),
+                ));
+            }
+        }
+
+        let mut wal = self.wal.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        let in_range = |key: &String| files.iter().any(|(s, l, _)| key >= s && key <= l);
+        let overlaps = |memtable: &MemTable| memtable.iter().any(|(key, _)| in_range(key));
+        if overlaps(&state.memtable) || state.immutable.iter().any(|f| overlaps(&f.memtable)) {
+            drop(state);
+            self.flush_memtable(&mut wal)?;
+            state = self.state.lock().unwrap();
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+        let sequence = state.last_sequence + 1;
+
+        let mut tables = Vec::with_capacity(files.len());
+        for (i, (_, _, src)) in files.iter().enumerate() {
+            let path = self
+                .path
+                .join(format!("sstable_ingested_{}_{:04}.sst", timestamp, i));
+            let tmp_path = path.with_extension("sst.tmp");
+            fs::copy(src, &tmp_path)?;
+            fs::File::open(&tmp_path)?.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+
+            let mut table = self.open_sstable(&path)?;
+            table.set_global_sequence(sequence);
+            tables.push(table);
+        }
+
+        state
+            .tables_mut()
+            .splice(0..0, tables.into_iter().map(Arc::new));
+        self.write_manifest(&state)?;
+
+        state.last_sequence = sequence;
+        if state.memtable.is_empty() && state.immutable.is_empty() {
+            state.flushed_sequence = sequence;
+        }
+        drop(state);
+        self.schedule(Job::Compaction);
+        Ok(())
+    }
+
+    fn checkpoint(&self, dir: &Path) -> io::Result<()> {
+        if dir.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dir.display()),
+            ));
+        }
+
+        let mut wal = self.wal.lock().unwrap();
+        self.flush_memtable(&mut wal)?;
+
+        // Marking the tables busy keeps compactions and blob garbage
+        // collection from deleting or rewriting them while they are copied
+        let mut state = self.wait_for_compactions(self.state.lock().unwrap());
+        let tables = state.sstables.clone();
+        state
+            .compacting
+            .extend(tables.iter().map(|t| t.path().to_path_buf()));
+        drop((wal, state));
+
+        let result = self.copy_tables(&tables, dir);
+
+        let mut state = self.state.lock().unwrap();
+        for table in tables.iter() {
+            state.compacting.remove(table.path());
+        }
+        drop(state);
+        self.job_done.notify_all();
+        result
+    }
+
+    /// Writes copies of `tables` and a manifest listing them into `dir`.
+    fn copy_tables(&self, tables: &[Arc<SSTableReader>], dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let mut entries = Vec::with_capacity(tables.len());
+        for table in tables {
+            let name = table.path().file_name().unwrap();
+            self.copy_table(table, &dir.join(name))?;
+            entries.push(TableEntry {
+                name: name.to_string_lossy().into_owned(),
+                global_sequence: table.global_sequence().unwrap_or(0),
+                level: table.level(),
+            });
+        }
+
+        Manifest {
+            tables: entries,
+            log_number: 0,
+        }
+        .write(dir)
+    }
+
+    /// Copies a table to `dest`, with the values it keeps in blob files
+    /// moved back inline.
+    fn copy_table(&self, table: &SSTableReader, dest: &Path) -> io::Result<()> {
+        if table.properties().is_some_and(|p| p.num_blob_refs == 0) {
+            fs::copy(table.path(), dest)?;
+        } else {
+            let mut builder = self.new_inline_table_builder(dest)?;
+            if let Some(p) = table.properties() {
+                builder.set_sequence_range(p.min_sequence, p.max_sequence);
+            }
+            builder.set_creation_time(compaction::creation_time(table));
+
+            for entry in SSTableReader::new(table.path())? {
+                match entry? {
+                    (key, TableValue::Blob(pointer)) => {
+                        builder.add(&key, &self.blob_store.get(pointer)?)?
+                    }
+                    (key, TableValue::Inline(value)) => builder.add(&key, &value)?,
+                    (key, TableValue::Tombstone) => builder.delete(&key)?,
+                }
+            }
+            builder.finish()?;
+        }
+        fs::File::open(dest)?.sync_all()
+    }
+
+    fn restore_to(&self, backup: &Path, target: RestoreTarget) -> io::Result<()> {
+        let manifest = Manifest::load(backup)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} holds no checkpoint", backup.display()),
+            )
+        })?;
+
+        // Nothing may be flushed or compacted into the tables being replaced
+        let mut wal = self.wal.lock().unwrap();
+        let _flushing = self.flush_lock.lock().unwrap();
+        let mut state = self.wait_for_compactions(self.state.lock().unwrap());
+
+        // The newest write the checkpoint holds, timed by the newest table
+        // until its WAL record turns up
+        let mut checkpoint_sequence = 0;
+        let mut checkpoint_time = 0;
+        for entry in &manifest.tables {
+            let mut table = SSTableReader::new(backup.join(&entry.name))?;
+            if entry.global_sequence > 0 {
+                table.set_global_sequence(entry.global_sequence);
+            }
+            checkpoint_sequence = checkpoint_sequence.max(table_sequence(&table));
+            checkpoint_time = checkpoint_time.max(compaction::creation_time(&table));
+        }
+
+        let mut segments = wal::segments(&self.path)?;
+        if let Some(archive) = &self.options.wal_archive_dir
+            && archive.exists()
+        {
+            segments.extend(wal::segments(archive)?);
+        }
+        segments.sort();
+
+        let mut memtable = MemTable::new();
+        let mut sequence = checkpoint_sequence;
+        let mut past_target = false;
+        'replay: for (_, path) in segments {
+            for entry in WALIterator::with_recovery_mode(&path, self.options.wal_recovery_mode)? {
+                let record = entry?;
+                if checkpoint_sequence > 0 && record.sequence == checkpoint_sequence {
+                    checkpoint_time = record.timestamp;
+                }
+                // Records without a sequence predate every checkpoint, and a
+                // segment may have been archived while still in the WAL
+                if record.sequence <= sequence {
+                    continue;
+                }
+                // Writes up to the target went missing, through an expired
+                // archive segment or an ingestion after the checkpoint
+                if record.sequence != sequence + 1 && !target.reached(sequence) {
+                    return Err(missing_writes(sequence + 1, record.sequence - 1));
+                }
+                if !target.includes(&record) {
+                    past_target = true;
+                    break 'replay;
+                }
+
+                match record.value {
+                    Some(value) => memtable.set(record.key, value),
+                    None => memtable.del(record.key),
+                }
+                sequence = record.sequence;
+            }
+        }
+        if !past_target && sequence < state.last_sequence && !target.reached(sequence) {
+            return Err(missing_writes(sequence + 1, state.last_sequence));
+        }
+        if target.precedes(checkpoint_sequence, checkpoint_time) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Restore target precedes the checkpoint at sequence {}",
+                    checkpoint_sequence
+                ),
+            ));
+        }
 
-        // 3. Start new SSTable
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_micros();
-        let new_sst_name = format!("sstable_compacted_{}.sst", timestamp);
-        let new_sst_path = self.path.join(&new_sst_name);
+        let mut tables = Vec::with_capacity(manifest.tables.len());
+        for (i, entry) in manifest.tables.iter().enumerate() {
+            let path = self
+                .path
+                .join(format!("sstable_restored_{}_{:04}.sst", timestamp, i));
+            fs::copy(backup.join(&entry.name), &path)?;
+            fs::File::open(&path)?.sync_all()?;
+
+            let mut table = self.open_sstable(&path)?;
+            if entry.global_sequence > 0 {
+                table.set_global_sequence(entry.global_sequence);
+            }
+            table.set_level(entry.level);
+            tables.push(table);
+        }
+
+        let old_paths: Vec<PathBuf> = state
+            .sstables
+            .iter()
+            .map(|t| t.path().to_path_buf())
+            .collect();
+        let replayed = !memtable.is_empty();
+        state.sstables = Arc::new(tables.into_iter().map(Arc::new).collect());
+        state.memtable = memtable;
+        state.immutable.clear();
+        state.flushed_sequence = checkpoint_sequence;
+        state.last_sequence = sequence;
+
+        // Retires every current segment, whatever got replayed from them
+        if replayed {
+            self.freeze_memtable(&mut state, &mut wal)?;
+            let frozen = state.immutable[0].clone();
+            let tables = self.write_memtable(&frozen)?;
+            self.install_flush(&mut state, tables)?;
+        } else {
+            wal.rotate()?;
+            state.log_number = wal.number();
+            self.write_manifest(&state)?;
+            self.retire_segments(state.log_number)?;
+        }
+
+        for path in old_paths {
+            fs::remove_file(path)?;
+        }
+        // Only cut the archive once the restored state is durable
+        if let Some(archive) = &self.options.wal_archive_dir
+            && archive.exists()
+        {
+            wal::trim_archive(archive, sequence)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_manifest(&self, state: &State) -> io::Result<()> {
+        let tables = state
+            .sstables
+            .iter()
+            .map(|t| TableEntry {
+                name: t.path().file_name().unwrap().to_string_lossy().into_owned(),
+                global_sequence: t.global_sequence().unwrap_or(0),
+                level: t.level(),
+            })
+            .collect();
+        Manifest {
+            tables,
+            log_number: state.log_number,
+        }
+        .write(&self.path)
+    }
+
+    /// Fails as if the process had crashed, if a test asked for it at `point`.
+    #[cfg(feature = "failpoints")]
+    fn crash_point(&self, point: CrashPoint) -> io::Result<()> {
+        if self.options.crash_point == Some(point) {
+            return Err(io::Error::other(format!("Crashed at {:?}", point)));
+        }
+        Ok(())
+    }
+
+    /// Blocks until no compaction is merging tables.
+    fn wait_for_compactions<'a>(&self, mut state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        while !state.compacting.is_empty() {
+            state = self.job_done.wait(state).unwrap();
+        }
+        state
+    }
+
+    fn compact(&self) -> io::Result<()> {
+        self.flush_memtable(&mut self.wal.lock().unwrap())?;
+
+        let mut state = self.wait_for_compactions(self.state.lock().unwrap());
+        if state.sstables.is_empty() {
+            return Ok(());
+        }
+
+        let max_level = state.sstables.iter().map(|t| t.level()).max().unwrap_or(0);
+        let job = CompactionJob {
+            inputs: (0..state.sstables.len()).collect(),
+            output_level: max_level.max(1),
+            drop_tombstones: true,
+            split_output: true,
+            cursor: None,
+        };
+        // Update timestamp
+        state.last_compaction_time = SystemTime::now();
+        self.run_compaction(state, job)?;
+        self.read_stats.reset();
+
+        self.collect_blob_garbage()
+    }
+
+    fn compact_range(&self, start: &str, end: &str) -> io::Result<()> {
+        self.flush_memtable(&mut self.wal.lock().unwrap())?;
+
+        let state = self.wait_for_compactions(self.state.lock().unwrap());
+        let max_level = state.sstables.iter().map(|t| t.level()).max().unwrap_or(0);
+        let Some(job) = compaction::pick_range(&state.sstables, start, end, max_level.max(1))
+        else {
+            return Ok(());
+        };
+        self.run_compaction(state, job)?;
+
+        self.collect_blob_garbage()
+    }
+
+    /// Merges the job's input tables into new tables in its output level,
+    /// then swaps them for the inputs.
+    ///
+    /// `state` is only held to mark the inputs busy and to swap the tables,
+    /// so reads, writes, flushes and compactions of other tables go on while
+    /// the merge runs.
+    fn run_compaction(
+        &self,
+        mut state: MutexGuard<'_, State>,
+        job: CompactionJob,
+    ) -> io::Result<()> {
+        let inputs: Vec<Arc<SSTableReader>> = job
+            .inputs
+            .iter()
+            .map(|&i| state.sstables[i].clone())
+            .collect();
+        state
+            .compacting
+            .extend(inputs.iter().map(|t| t.path().to_path_buf()));
+        drop(state);
+
+        let result = self.write_compaction(&inputs, &job);
+
+        let mut state = self.state.lock().unwrap();
+        for table in &inputs {
+            state.compacting.remove(table.path());
+        }
+        self.job_done.notify_all();
+        let outputs = result?;
+
+        // Swap the new tables in where the first input was, which keeps level
+        // 0 in precedence order
+        let mut outputs = Some(outputs);
+        let mut tables = Vec::with_capacity(state.sstables.len());
+        for table in state.sstables.iter() {
+            if inputs.iter().any(|input| Arc::ptr_eq(input, table)) {
+                tables.extend(outputs.take().into_iter().flatten().map(Arc::new));
+            } else {
+                tables.push(table.clone());
+            }
+        }
+        compaction::sort_tables(&mut tables);
+        state.sstables = Arc::new(tables);
+        self.write_manifest(&state)?;
+        #[cfg(feature = "failpoints")]
+        self.crash_point(CrashPoint::ManifestWritten)?;
+
+        if let Some((level, key)) = job.cursor {
+            state.compact_cursors.insert(level, key);
+        }
+        drop(state);
+
+        // Only now that the manifest no longer lists the inputs; should this
+        // stop halfway, opening the database deletes the rest
+        for table in &inputs {
+            fs::remove_file(table.path())?;
+            #[cfg(feature = "failpoints")]
+            if Arc::ptr_eq(table, &inputs[0]) {
+                self.crash_point(CrashPoint::InputDeleted)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_compaction(
+        &self,
+        inputs: &[Arc<SSTableReader>],
+        job: &CompactionJob,
+    ) -> io::Result<Vec<SSTableReader>> {
+        let min_sequence = inputs
+            .iter()
+            .filter_map(|t| t.properties())
+            .map(|p| p.min_sequence)
+            .min()
+            .unwrap_or(0);
+        let max_sequence = inputs.iter().map(|t| table_sequence(t)).max().unwrap_or(0);
+        let creation_time = inputs
+            .iter()
+            .map(|t| compaction::creation_time(t))
+            .max()
+            .unwrap_or(0);
+
+        let mut writer = TableWriter::new(self, "sstable_compacted");
+        writer.set_sequence_range(min_sequence, max_sequence);
+        writer.set_creation_time(creation_time);
+        if !job.split_output {
+            writer.set_target_file_size(u64::MAX);
+        }
+        self.merge_tables(inputs, &mut writer, job.output_level, job.drop_tombstones)?;
+        let new_tables = writer.finish()?;
+
+        let mut outputs = Vec::with_capacity(new_tables.len());
+        for path in &new_tables {
+            let mut table = self.open_sstable(path)?;
+            table.set_level(job.output_level);
+            outputs.push(table);
+        }
+        Ok(outputs)
+    }
 
-        let mut builder = SSTableBuilder::new(&new_sst_path)?;
+    /// Deletes the tables at `positions` along with everything they hold.
+    fn drop_tables(&self, state: &mut State, positions: &[usize]) -> io::Result<()> {
+        let mut old_paths = Vec::with_capacity(positions.len());
+        let mut tables = Vec::with_capacity(state.sstables.len());
+        for (i, table) in state.sstables.iter().enumerate() {
+            if positions.contains(&i) {
+                old_paths.push(table.path().to_path_buf());
+            } else {
+                tables.push(table.clone());
+            }
+        }
+        state.sstables = Arc::new(tables);
+        self.write_manifest(state)?;
+
+        for path in old_paths {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the newest version of every key in `inputs`, which must be in
+    /// precedence order, after passing it through the compaction filter.
+    fn merge_tables(
+        &self,
+        inputs: &[Arc<SSTableReader>],
+        writer: &mut TableWriter,
+        level: u32,
+        drop_tombstones: bool,
+    ) -> io::Result<()> {
+        let mut iters = Vec::with_capacity(inputs.len());
+        for table in inputs {
+            iters.push(SSTableReader::new(table.path())?.into_iter().peekable());
+        }
 
-        // 5. Merge Loop
         loop {
             // Find the iterator with the smallest key
             let mut best_idx = None;
             let mut min_key: Option<&String> = None;
 
             for (i, iter) in iters.iter_mut().enumerate() {
-                if let Some(Ok((key, _))) = iter.peek() {
-                    if min_key.map_or(true, |mk| key < mk) {
+                match iter.peek() {
+                    Some(Ok((key, _))) if min_key.is_none_or(|mk| key < mk) => {
                         min_key = Some(key);
                         best_idx = Some(i);
                     }
+                    Some(Err(_)) => return Err(iter.next().unwrap().unwrap_err()),
+                    _ => {}
                 }
             }
 
-            if let Some(idx) = best_idx {
-                // Consume the element from best_idx
-                let (key, val) = iters[idx].next().unwrap()?;
-                eprintln!("Selected from Iter {}: key={}, val={:?}", idx, key, val);
+            let Some(idx) = best_idx else {
+                // No more elements
+                break;
+            };
+
+            let (key, mut val) = iters[idx].next().unwrap()?;
+            if let Some(filter) = &self.options.compaction_filter {
+                let decision = match &val {
+                    TableValue::Inline(v) => filter.filter(level, &key, v),
+                    TableValue::Blob(pointer) => {
+                        filter.filter(level, &key, &self.blob_store.get(*pointer)?)
+                    }
+                    TableValue::Tombstone => FilterDecision::Keep,
+                };
+                match decision {
+                    FilterDecision::Keep => {}
+                    // Older versions below the output may still need shadowing
+                    FilterDecision::Remove => val = TableValue::Tombstone,
+                    FilterDecision::ChangeValue(v) => val = TableValue::Inline(v),
+                }
+            }
 
-                if let Some(v) = val {
-                    builder.add(&key, &v)?;
+            match val {
+                TableValue::Inline(v) => writer.builder()?.add(&key, &v)?,
+                TableValue::Blob(pointer) => writer.builder()?.add_blob_ref(&key, pointer)?,
+                TableValue::Tombstone if !drop_tombstones => writer.builder()?.delete(&key)?,
+                TableValue::Tombstone => {}
+            }
+
+            // Advance other iterators if they have the same key
+            for (i, iter) in iters.iter_mut().enumerate() {
+                if i == idx {
+                    continue;
                 }
 
-                // Advance other iterators if they have the same key
-                for (i, iter) in iters.iter_mut().enumerate() {
-                    if i == idx {
-                        continue;
-                    }
+                if let Some(Ok((k, _))) = iter.peek()
+                    && k == &key
+                {
+                    iter.next(); // Discard shadowed version
+                }
+            }
+        }
+        Ok(())
+    }
 
-                    if let Some(Ok((k, _))) = iter.peek() {
-                        if k == &key {
-                            iter.next(); // Discard shadowed version
-                        }
-                    }
+    /// Runs blob garbage collection once no flush or compaction is writing
+    /// pointers to blobs it might move.
+    fn collect_blob_garbage(&self) -> io::Result<()> {
+        let _flushing = self.flush_lock.lock().unwrap();
+
+        // Mark the tables pointing into blob files busy, so that no
+        // compaction copies pointers to files about to be deleted, and
+        // collect unlocked
+        let mut state = self.wait_for_compactions(self.state.lock().unwrap());
+        let tables: Vec<Arc<SSTableReader>> = state
+            .sstables
+            .iter()
+            .filter(|t| t.properties().is_none_or(|p| p.num_blob_refs > 0))
+            .cloned()
+            .collect();
+        state
+            .compacting
+            .extend(tables.iter().map(|t| t.path().to_path_buf()));
+        drop(state);
+
+        let mut relocated = Vec::new();
+        let result = self.collect_garbage(&tables, &mut relocated);
+
+        let mut state = self.state.lock().unwrap();
+        for table in &tables {
+            state.compacting.remove(table.path());
+        }
+        self.job_done.notify_all();
+
+        // Tables rewritten before an error are swapped in all the same
+        for (old, new) in relocated {
+            if let Some(slot) = state.tables_mut().iter_mut().find(|t| Arc::ptr_eq(t, &old)) {
+                *slot = Arc::new(new);
+            }
+        }
+
+        // Readers may still hold tables pointing into the dead files, so they
+        // are only deleted once the last of those readers is done
+        let dead = result?;
+        if !dead.is_empty() {
+            state.retire_read_epoch(dead);
+        }
+        Ok(())
+    }
+
+    /// Finds the blob files no table in `tables` points into, and rewrites
+    /// the tables pointing into mostly dead ones.
+    ///
+    /// Rewritten tables are added to `relocated` along with the ones they
+    /// replace. Returns the blob files to delete once they are swapped in,
+    /// the unused ones as well as those the values were moved out of.
+    fn collect_garbage(
+        &self,
+        tables: &[Arc<SSTableReader>],
+        relocated: &mut Vec<(Arc<SSTableReader>, SSTableReader)>,
+    ) -> io::Result<HashSet<u64>> {
+        let mut live_bytes: HashMap<u64, u64> = HashMap::new();
+        for table in tables {
+            for entry in SSTableReader::new(table.path())? {
+                if let (_, TableValue::Blob(pointer)) = entry? {
+                    *live_bytes.entry(pointer.file).or_default() += pointer.len as u64;
                 }
-            } else {
-                // No more elements
-                break;
             }
         }
 
-        builder.finish()?;
+        let active = self.blob_store.active_file();
+        let mut dead = HashSet::new();
+        let mut victims = HashSet::new();
+        for number in self.blob_store.files()? {
+            if Some(number) == active {
+                continue;
+            }
 
-        // 6. Delete old files
-        for entry in fs::read_dir(&self.path)? {
-            let entry = entry?;
-            let path = entry.path();
-            if let Some(ext) = path.extension() {
-                if ext != "sst" {
-                    continue;
+            let live = live_bytes.get(&number).copied().unwrap_or(0);
+            let size = fs::metadata(self.blob_store.file_path(number))?.len();
+            if live == 0 {
+                dead.insert(number);
+            } else if (live as f64) < size as f64 * self.options.blob_gc_ratio {
+                victims.insert(number);
+            }
+        }
+
+        if !victims.is_empty() {
+            for table in tables {
+                if let Some(new) = self.relocate_blobs(table, &victims)? {
+                    relocated.push((table.clone(), new));
                 }
+            }
+        }
+        dead.extend(victims);
+        Ok(dead)
+    }
 
-                if path.file_name() != Some(std::ffi::OsStr::new(&new_sst_name)) {
-                    fs::remove_file(path)?;
+    /// Rewrites a table in place, moving values out of the `victims` blob
+    /// files. Returns the rewritten table, or `None` if it had no values
+    /// there.
+    fn relocate_blobs(
+        &self,
+        table: &SSTableReader,
+        victims: &HashSet<u64>,
+    ) -> io::Result<Option<SSTableReader>> {
+        let path = table.path().to_path_buf();
+        let tmp_path = path.with_extension("sst.tmp");
+
+        let mut builder = self.new_table_builder(&tmp_path)?;
+        if let Some(p) = table.properties() {
+            builder.set_sequence_range(p.min_sequence, p.max_sequence);
+        }
+        builder.set_creation_time(compaction::creation_time(table));
+
+        let mut relocated = false;
+        for entry in SSTableReader::new(&path)? {
+            match entry? {
+                (key, TableValue::Blob(pointer)) if victims.contains(&pointer.file) => {
+                    let value = self.blob_store.get(pointer)?;
+                    builder.add_blob_ref(&key, self.blob_store.put(&value)?)?;
+                    relocated = true;
                 }
+                (key, TableValue::Blob(pointer)) => builder.add_blob_ref(&key, pointer)?,
+                (key, TableValue::Inline(value)) => builder.add(&key, &value)?,
+                (key, TableValue::Tombstone) => builder.delete(&key)?,
             }
         }
 
-        // 7. Update self.sstables
-        self.sstables = vec![SSTableReader::new(new_sst_path)?];
+        if !relocated {
+            drop(builder);
+            fs::remove_file(tmp_path)?;
+            return Ok(None);
+        }
 
-        // Update timestamp
-        self.last_compaction_time = SystemTime::now();
+        self.blob_store.seal()?;
+        builder.finish()?;
+        // Readers still holding the old table keep reading its file
+        fs::rename(&tmp_path, &path)?;
+        let mut relocated = self.open_sstable(&path)?;
+        relocated.set_level(table.level());
+        if let Some(sequence) = table.global_sequence() {
+            relocated.set_global_sequence(sequence);
+        }
+        Ok(Some(relocated))
+    }
+}
 
-        Ok(())
+impl State {
+    /// The table list, and the epoch that keeps what it points into around.
+    fn snapshot(&self) -> (Arc<Vec<Arc<SSTableReader>>>, Arc<ReadEpoch>) {
+        (self.sstables.clone(), self.read_epoch.clone())
+    }
+
+    /// Ends the current read epoch, so the `dead` blob files are deleted
+    /// once no reader holds a table list from before now.
+    fn retire_read_epoch(&mut self, dead: HashSet<u64>) {
+        let next = Arc::new(ReadEpoch::new(self.read_epoch.blob_store.clone()));
+        let ended = std::mem::replace(&mut self.read_epoch, next.clone());
+        let _ = ended.retired.set((next, dead));
+    }
+
+    /// The table list, copied first if a reader still holds on to it.
+    fn tables_mut(&mut self) -> &mut Vec<Arc<SSTableReader>> {
+        Arc::make_mut(&mut self.sstables)
+    }
+
+    /// Whether a running compaction is merging any of the tables at
+    /// `positions`.
+    fn is_busy(&self, positions: &[usize]) -> bool {
+        positions
+            .iter()
+            .any(|&i| self.compacting.contains(self.sstables[i].path()))
+    }
+}
+
+/// Highest sequence number covered by a table, or 0 if it doesn't record one.
+fn missing_writes(first: u64, last: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("The WAL is missing writes {}..={}", first, last),
+    )
+}
+
+fn table_sequence(table: &SSTableReader) -> u64 {
+    table
+        .global_sequence()
+        .or_else(|| table.properties().map(|p| p.max_sequence))
+        .unwrap_or(0)
+}
+
+/// Table files in `dir`, including temporary ones from unfinished writes.
+fn list_table_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if name.ends_with(".sst") || name.ends_with(".sst.tmp") {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Checks that an external table holds strictly increasing keys and no blob
+/// pointers, and returns its smallest and largest key.
+fn validate_external_file(path: &Path) -> io::Result<(String, String)> {
+    let invalid = |msg: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: {}", path.display(), msg),
+        )
+    };
+
+    let mut range: Option<(String, String)> = None;
+    for entry in SSTableReader::new(path)? {
+        let (key, value) = entry?;
+        if let TableValue::Blob(_) = value {
+            return Err(invalid("Ingested tables cannot point into blob files"));
+        }
+
+        match &mut range {
+            Some((_, largest)) if key <= *largest => {
+                return Err(invalid("Keys are not in increasing order"));
+            }
+            Some((_, largest)) => *largest = key,
+            None => range = Some((key.clone(), key)),
+        }
+    }
+
+    range.ok_or_else(|| invalid("Table has no entries"))
+}
+
+/// Writes a sorted run of entries into as many SSTables as it takes to keep
+/// each one near `Options::target_file_size`.
+///
+/// A new file is only started right before an entry is added, so a key never
+/// spans two files and the outputs cover disjoint key ranges. Files are
+/// written under temporary names and only get their own once all of them are
+/// complete, so a crash never leaves a partial table that looks finished.
+struct TableWriter<'a> {
+    db: &'a Inner,
+    name: String,
+    target_file_size: u64,
+    sequence_range: Option<(u64, u64)>,
+    creation_time: Option<u64>,
+    builder: Option<SSTableBuilder>,
+    paths: Vec<PathBuf>,
+}
+
+impl<'a> TableWriter<'a> {
+    fn new(db: &'a Inner, prefix: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        Self {
+            db,
+            name: format!("{}_{}", prefix, timestamp),
+            target_file_size: db.options.target_file_size,
+            sequence_range: None,
+            creation_time: None,
+            builder: None,
+            paths: Vec::new(),
+        }
+    }
+
+    fn set_sequence_range(&mut self, min: u64, max: u64) {
+        self.sequence_range = Some((min, max));
+    }
+
+    fn set_creation_time(&mut self, micros: u64) {
+        self.creation_time = Some(micros);
+    }
+
+    fn set_target_file_size(&mut self, size: u64) {
+        self.target_file_size = size;
+    }
+
+    /// Builder for the next entry, rolling over to a new file if the
+    /// current one has reached the target size.
+    fn builder(&mut self) -> io::Result<&mut SSTableBuilder> {
+        if self
+            .builder
+            .as_ref()
+            .is_some_and(|b| b.file_size() >= self.target_file_size)
+        {
+            self.builder.take().unwrap().finish()?;
+        }
+
+        if self.builder.is_none() {
+            let path = self
+                .db
+                .path
+                .join(format!("{}_{:04}.sst", self.name, self.paths.len()));
+            let mut builder = self.db.new_table_builder(&path.with_extension("sst.tmp"))?;
+            if let Some((min, max)) = self.sequence_range {
+                builder.set_sequence_range(min, max);
+            }
+            if let Some(micros) = self.creation_time {
+                builder.set_creation_time(micros);
+            }
+            self.builder = Some(builder);
+            self.paths.push(path);
+        }
+
+        Ok(self.builder.as_mut().unwrap())
+    }
+
+    /// Finishes the last file, renames all files written to their final
+    /// names and returns those, in key order.
+    ///
+    /// The files aren't part of the database until the caller records them
+    /// in the manifest; until then, reopening it deletes them.
+    fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        if let Some(builder) = self.builder.take() {
+            builder.finish()?;
+        }
+        #[cfg(feature = "failpoints")]
+        self.db.crash_point(CrashPoint::TablesWritten)?;
+
+        for path in &self.paths {
+            fs::rename(path.with_extension("sst.tmp"), path)?;
+        }
+        #[cfg(feature = "failpoints")]
+        self.db.crash_point(CrashPoint::TablesRenamed)?;
+        Ok(self.paths)
     }
 }
@@ -1,6 +1,17 @@
+pub(crate) mod background;
+pub mod blob;
+pub mod cache;
+pub mod compaction;
 pub mod database;
+pub(crate) mod manifest;
 pub mod memtable;
+pub mod options;
+pub mod prefix;
+pub mod rate_limiter;
 pub mod sstable;
 pub mod wal;
 
-pub use database::{CompactionPolicy, Database};
+pub use compaction::CompactionTriggers;
+pub use database::{CompactionPolicy, Database, RestoreTarget};
+pub use options::{Options, WriteOptions};
+pub use sstable::SstFileWriter;
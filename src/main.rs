@@ -1,7 +1,7 @@
 use janql::Database;
 
 fn main() {
-    let mut db = Database::new("example.db");
+    let db = Database::new("example.db");
     
     db.set("key1".to_string(), "value1".to_string());
     
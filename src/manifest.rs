@@ -0,0 +1,106 @@
+//! The set of live SSTables, in precedence order.
+//!
+//! The manifest is rewritten as a whole into a temporary file that is then
+//! renamed over `MANIFEST`, so every change to the table set (flush,
+//! compaction, ingestion) becomes visible atomically. Tables on disk that the
+//! manifest doesn't list are leftovers of an interrupted change.
+//!
+//! It also records the oldest WAL segment that still holds writes missing
+//! from the tables. Manifests written before segments existed end after the
+//! table list and read as segment 0, and those written before levels existed
+//! put every table in level 0.
+
+use std::fs::{self, File};
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+
+use crate::sstable::format::{get_str, get_u32, get_u64, invalid_data, put_str, put_u32, put_u64};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+const MAGIC_V1: u64 = 0x4a61_6e51_4c4d_4654; // "JanQLMFT"
+const MAGIC: u64 = 0x4a61_6e51_4c4d_4632; // "JanQLMF2"
+
+/// A live table as recorded in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TableEntry {
+    /// File name within the database directory.
+    pub name: String,
+    /// Sequence number assigned to an ingested table, which covers all of
+    /// its entries. Zero for tables that record their own range.
+    pub global_sequence: u64,
+    /// Compaction level; level 0 tables may overlap each other.
+    pub level: u32,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    /// Newest first.
+    pub tables: Vec<TableEntry>,
+    /// WAL segments below this number are covered by the tables.
+    pub log_number: u64,
+}
+
+impl Manifest {
+    /// Reads the manifest in `dir`, or `None` for databases that predate it.
+    pub fn load(dir: &Path) -> io::Result<Option<Self>> {
+        let buf = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut cursor = Cursor::new(buf.as_slice());
+        let has_levels = match get_u64(&mut cursor)? {
+            MAGIC => true,
+            MAGIC_V1 => false,
+            _ => return Err(invalid_data("Bad manifest magic")),
+        };
+
+        let count = get_u32(&mut cursor)?;
+        let mut tables = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = get_str(&mut cursor)?;
+            let global_sequence = get_u64(&mut cursor)?;
+            let level = if has_levels { get_u32(&mut cursor)? } else { 0 };
+            tables.push(TableEntry {
+                name,
+                global_sequence,
+                level,
+            });
+        }
+
+        let log_number = if (cursor.position() as usize) < buf.len() {
+            get_u64(&mut cursor)?
+        } else {
+            0
+        };
+
+        Ok(Some(Self { tables, log_number }))
+    }
+
+    /// Atomically replaces the manifest in `dir`.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        let mut buf = Vec::new();
+        put_u64(&mut buf, MAGIC);
+        put_u32(&mut buf, self.tables.len() as u32);
+        for table in &self.tables {
+            put_str(&mut buf, &table.name);
+            put_u64(&mut buf, table.global_sequence);
+            put_u32(&mut buf, table.level);
+        }
+        put_u64(&mut buf, self.log_number);
+
+        let tmp_path = dir.join(MANIFEST_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir.join(MANIFEST_FILE))?;
+
+        // Make the rename itself durable
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+}
@@ -16,12 +16,12 @@ impl MemTable {
     pub fn set(&mut self, key: String, value: String) {
         let key_len = key.len();
         let val_len = value.len();
-        
+
         if let Some(old_val) = self.map.insert(key, Some(value)) {
-            if let Some(v) = old_val {
-                if self.size_bytes >= v.len() {
-                    self.size_bytes -= v.len();
-                }
+            if let Some(v) = old_val
+                && self.size_bytes >= v.len()
+            {
+                self.size_bytes -= v.len();
             }
         } else {
             self.size_bytes += key_len;
@@ -36,7 +36,7 @@ impl MemTable {
     pub fn del(&mut self, key: String) {
         let key_len = key.len();
         if self.map.insert(key, None).is_none() {
-             self.size_bytes += key_len;
+            self.size_bytes += key_len;
         }
     }
 
@@ -44,6 +44,10 @@ impl MemTable {
         self.map.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     pub fn size_bytes(&self) -> usize {
         self.size_bytes
     }
@@ -51,9 +55,15 @@ impl MemTable {
     pub fn iter(&self) -> std::collections::btree_map::Iter<'_, String, Option<String>> {
         self.map.iter()
     }
-    
+
     pub fn clear(&mut self) {
         self.map.clear();
         self.size_bytes = 0;
     }
 }
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::compaction::CompactionFilter;
+#[cfg(feature = "failpoints")]
+use crate::compaction::CrashPoint;
+use crate::prefix::PrefixExtractor;
+use crate::rate_limiter::RateLimiter;
+use crate::wal::{WalRecoveryMode, WalSyncMode};
+
+/// Tuning knobs applied when a database is opened.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Bytes of SSTable blocks kept in the shared block cache.
+    pub block_cache_capacity: usize,
+    /// Serve SSTable reads from memory-mapped files instead of the block cache.
+    pub mmap_reads: bool,
+    /// Write SSTables with a two-level index split into partitions of about
+    /// this many bytes. Only the top level stays in memory; partitions are
+    /// read on demand through the block cache.
+    pub index_partition_size: Option<usize>,
+    /// End every data block with a hash index from key to entry, so point
+    /// lookups skip the key comparisons of a block scan. Costs a few bytes
+    /// per entry and doesn't help prefix scans.
+    pub block_hash_index: bool,
+    /// When WAL writes are synced to disk. See [`WalSyncMode`] for what each
+    /// mode can lose.
+    pub wal_sync_mode: WalSyncMode,
+    /// How damaged WAL records are handled when the database is opened.
+    pub wal_recovery_mode: WalRecoveryMode,
+    /// Move WAL segments here once their writes are in SSTables, instead of
+    /// deleting them, so that [`Database::restore_to`](crate::Database::restore_to)
+    /// can replay them on top of a checkpoint.
+    pub wal_archive_dir: Option<PathBuf>,
+    /// Archived segments last written longer ago than this are deleted.
+    /// `None` keeps them forever.
+    pub wal_archive_ttl: Option<Duration>,
+    /// Flush and compaction start a new SSTable once the current one holds
+    /// about this many bytes of data. Files are only split between keys.
+    pub target_file_size: u64,
+    /// Groups keys by prefix so that SSTables carry a prefix bloom filter,
+    /// which lets prefix scans skip tables holding no keys under the prefix.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Store values of at least this many bytes in separate blob files, so
+    /// that compaction only moves pointers to them around.
+    pub blob_threshold: Option<usize>,
+    /// Blob files whose live bytes drop below this fraction of their size
+    /// are rewritten by blob garbage collection.
+    pub blob_gc_ratio: f64,
+    /// Called by compaction for every entry it keeps, to drop or rewrite it.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Threads flushing full memtables and running compactions in the
+    /// background. With 0, writes do both themselves before returning.
+    pub background_threads: usize,
+    /// How often the background threads check the compaction policy on
+    /// their own, besides after every flush. Unused without background
+    /// threads, where writes check it instead.
+    pub compaction_check_interval: Duration,
+    /// Limits how fast the background threads write SSTables. May be shared
+    /// between databases on the same disk.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Makes table writes and compactions fail at this step, as if the
+    /// process had crashed there.
+    #[cfg(feature = "failpoints")]
+    pub crash_point: Option<CrashPoint>,
+    /// Holds every group commit this long before it reaches the WAL, so
+    /// writes arriving meanwhile queue up behind its leader.
+    #[cfg(feature = "failpoints")]
+    pub commit_delay: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            block_cache_capacity: 8 * 1024 * 1024, // 8MB
+            mmap_reads: false,
+            index_partition_size: None,
+            block_hash_index: false,
+            wal_sync_mode: WalSyncMode::default(),
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_archive_dir: None,
+            wal_archive_ttl: None,
+            target_file_size: 64 * 1024 * 1024, // 64MB
+            prefix_extractor: None,
+            blob_threshold: None,
+            blob_gc_ratio: 0.5,
+            compaction_filter: None,
+            background_threads: 2,
+            compaction_check_interval: Duration::from_secs(1),
+            rate_limiter: None,
+            #[cfg(feature = "failpoints")]
+            crash_point: None,
+            #[cfg(feature = "failpoints")]
+            commit_delay: None,
+        }
+    }
+}
+
+/// Per-write settings.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Overrides the database's [`WalSyncMode`] for this write: `Some(true)`
+    /// syncs the WAL before returning, `Some(false)` leaves it unsynced.
+    /// Has no effect when the WAL is disabled.
+    pub sync: Option<bool>,
+}
@@ -0,0 +1,75 @@
+//! Key prefixes for prefix bloom filters.
+//!
+//! With [`Options::prefix_extractor`](crate::Options::prefix_extractor) set,
+//! every SSTable stores a bloom filter over the prefixes of its keys, and
+//! [`Database::get_by_prefix`](crate::Database::get_by_prefix) skips tables
+//! whose filter rules the scanned prefix out.
+
+use std::fmt;
+
+/// Maps a key to the prefix it is grouped under.
+///
+/// Implementations must be consistent with prefix scans: if `prefix(q)` is
+/// `Some(p)`, every key that starts with `q` must also have prefix `p`.
+/// Keys without a prefix are simply left out of the filter.
+pub trait PrefixExtractor: fmt::Debug + Send + Sync {
+    /// Identifies the extractor in the tables it wrote. Filters written by an
+    /// extractor with a different name are ignored.
+    fn name(&self) -> &str;
+
+    fn prefix<'a>(&self, key: &'a str) -> Option<&'a str>;
+}
+
+/// The first `len` bytes of the key. Shorter keys have no prefix.
+#[derive(Debug, Clone)]
+pub struct FixedPrefix {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefix {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            name: format!("janql.fixed.{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.get(..self.len)
+    }
+}
+
+/// Everything up to and including the first `delimiter`, e.g. `tenant42/`
+/// for `tenant42/orders/7`. Keys without the delimiter have no prefix.
+#[derive(Debug, Clone)]
+pub struct DelimitedPrefix {
+    delimiter: char,
+    name: String,
+}
+
+impl DelimitedPrefix {
+    pub fn new(delimiter: char) -> Self {
+        Self {
+            delimiter,
+            name: format!("janql.delimited.{}", delimiter),
+        }
+    }
+}
+
+impl PrefixExtractor for DelimitedPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.find(self.delimiter)
+            .map(|i| &key[..i + self.delimiter.len_utf8()])
+    }
+}
@@ -0,0 +1,183 @@
+//! Throttling the disk writes of background flushes and compactions.
+//!
+//! Set through [`Options::rate_limiter`](crate::Options::rate_limiter), a
+//! [`RateLimiter`] makes the SSTable writes of background jobs wait for
+//! tokens from a bucket refilled at a fixed number of bytes per second, so
+//! that a large compaction doesn't starve foreground reads of disk
+//! bandwidth. Flushes and compactions run by the caller, such as
+//! [`Database::compact`](crate::Database::compact), are not throttled.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The bucket holds at most this long's worth of writes.
+const BURST: Duration = Duration::from_millis(100);
+
+/// Auto-tuning adjusts the rate at most this often.
+const TUNE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A token bucket shared by every background job of a database, or of
+/// several databases.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    auto_tune: Option<AutoTune>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_sec: u64,
+    /// Tokens left; negative while writers wait for the bucket to refill.
+    available: f64,
+    last_refill: Instant,
+    total_bytes: u64,
+    /// Foreground latencies recorded since the last adjustment.
+    latency_sum: Duration,
+    latency_count: u32,
+    last_tune: Instant,
+}
+
+#[derive(Debug)]
+struct AutoTune {
+    min_bytes_per_sec: u64,
+    max_bytes_per_sec: u64,
+    target_latency: Duration,
+}
+
+impl RateLimiter {
+    /// Lets through `bytes_per_sec` bytes a second.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_auto_tune(bytes_per_sec, None)
+    }
+
+    /// Starts at `max_bytes_per_sec`, and adjusts the rate to the latency of
+    /// foreground reads.
+    ///
+    /// Whenever their average latency over the last interval exceeds
+    /// `target_latency`, the rate is cut by a quarter, down to
+    /// `min_bytes_per_sec`. Otherwise, including while there are no reads,
+    /// it grows back by a twentieth, up to `max_bytes_per_sec`.
+    pub fn auto_tuned(
+        min_bytes_per_sec: u64,
+        max_bytes_per_sec: u64,
+        target_latency: Duration,
+    ) -> Self {
+        let min_bytes_per_sec = min_bytes_per_sec.clamp(1, max_bytes_per_sec.max(1));
+        Self::with_auto_tune(
+            max_bytes_per_sec,
+            Some(AutoTune {
+                min_bytes_per_sec,
+                max_bytes_per_sec,
+                target_latency,
+            }),
+        )
+    }
+
+    fn with_auto_tune(bytes_per_sec: u64, auto_tune: Option<AutoTune>) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        let now = Instant::now();
+        Self {
+            bucket: Mutex::new(Bucket {
+                bytes_per_sec,
+                available: bytes_per_sec as f64 * BURST.as_secs_f64(),
+                last_refill: now,
+                total_bytes: 0,
+                latency_sum: Duration::ZERO,
+                latency_count: 0,
+                last_tune: now,
+            }),
+            auto_tune,
+        }
+    }
+
+    /// The rate writes are currently let through at.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_sec
+    }
+
+    /// Bytes let through so far.
+    pub fn total_bytes(&self) -> u64 {
+        self.bucket.lock().unwrap().total_bytes
+    }
+
+    /// Whether the rate follows foreground latency, which the database then
+    /// measures for it.
+    pub fn is_auto_tuned(&self) -> bool {
+        self.auto_tune.is_some()
+    }
+
+    /// Takes `bytes` tokens out of the bucket, blocking until the bucket has
+    /// refilled enough to cover them.
+    ///
+    /// Writes larger than the bucket go through whole; the writes after them
+    /// wait for the debt to be paid off.
+    pub fn request(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            if let Some(tune) = &self.auto_tune {
+                bucket.tune(tune, now);
+            }
+            bucket.refill(now);
+            bucket.available -= bytes as f64;
+            bucket.total_bytes += bytes;
+            if bucket.available < 0.0 {
+                Duration::from_secs_f64(-bucket.available / bucket.bytes_per_sec as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Records how long a foreground read took. Only auto-tuned limiters
+    /// use it.
+    pub fn record_latency(&self, latency: Duration) {
+        let Some(tune) = &self.auto_tune else {
+            return;
+        };
+
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.latency_sum += latency;
+        bucket.latency_count += 1;
+        bucket.tune(tune, Instant::now());
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let burst = self.bytes_per_sec as f64 * BURST.as_secs_f64();
+        self.available = (self.available + elapsed * self.bytes_per_sec as f64).min(burst);
+        self.last_refill = now;
+    }
+
+    /// Adjusts the rate once per interval to the foreground latency recorded
+    /// in it. Intervals without any reads count as fast ones.
+    fn tune(&mut self, tune: &AutoTune, now: Instant) {
+        if now.duration_since(self.last_tune) < TUNE_INTERVAL {
+            return;
+        }
+
+        let average = self
+            .latency_sum
+            .checked_div(self.latency_count)
+            .unwrap_or_default();
+        let rate = if average > tune.target_latency {
+            self.bytes_per_sec - self.bytes_per_sec / 4
+        } else {
+            self.bytes_per_sec + self.bytes_per_sec / 20 + 1
+        };
+
+        // Tokens gathered at the old rate are spent at it
+        self.refill(now);
+        self.bytes_per_sec = rate.clamp(tune.min_bytes_per_sec, tune.max_bytes_per_sec);
+        self.latency_sum = Duration::ZERO;
+        self.latency_count = 0;
+        self.last_tune = now;
+    }
+}
@@ -0,0 +1,253 @@
+//! Data block layout.
+//!
+//! A data block is a run of `[key_len u32][key][val_len u32][value]`
+//! entries. Tables written with a block hash index append a trailer to every
+//! data block:
+//!
+//! ```text
+//! [entries][bucket u16 x num_buckets][num_buckets u32][entries_len u32]
+//! ```
+//!
+//! Each bucket holds the offset of the only entry whose key hashes to it,
+//! `EMPTY_BUCKET` if there is none, or `COLLISION_BUCKET` if several keys
+//! share it, in which case lookups fall back to scanning the block.
+
+use std::io;
+
+use super::filter;
+use super::format::invalid_data;
+use super::{BLOB_REF, TOMBSTONE, TableValue};
+use crate::blob::{BLOB_POINTER_SIZE, BlobPointer};
+
+/// Meta block marking a table whose data blocks end with a hash index.
+pub(crate) const HASH_INDEX_BLOCK: &str = "janql.block.hash_index";
+
+const EMPTY_BUCKET: u16 = u16::MAX;
+const COLLISION_BUCKET: u16 = u16::MAX - 1;
+const HASH_TRAILER_SIZE: usize = 8;
+
+/// Buckets per entry; keeps most buckets holding a single key.
+const BUCKET_RATIO: f64 = 1.5;
+
+/// Value of a block entry, borrowed from the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockValue<'a> {
+    Inline(&'a str),
+    Blob(BlobPointer),
+    Tombstone,
+}
+
+impl BlockValue<'_> {
+    /// Bytes taken by the value, including its length prefix.
+    pub fn encoded_len(&self) -> usize {
+        4 + match self {
+            BlockValue::Inline(v) => v.len(),
+            BlockValue::Blob(_) => BLOB_POINTER_SIZE,
+            BlockValue::Tombstone => 0,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            BlockValue::Inline(v) => {
+                buf.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                buf.extend_from_slice(v.as_bytes());
+            }
+            BlockValue::Blob(pointer) => {
+                buf.extend_from_slice(&BLOB_REF.to_le_bytes());
+                pointer.encode(buf);
+            }
+            BlockValue::Tombstone => buf.extend_from_slice(&TOMBSTONE.to_le_bytes()),
+        }
+    }
+
+    pub fn to_owned(self) -> TableValue {
+        match self {
+            BlockValue::Inline(v) => TableValue::Inline(v.to_string()),
+            BlockValue::Blob(pointer) => TableValue::Blob(pointer),
+            BlockValue::Tombstone => TableValue::Tombstone,
+        }
+    }
+}
+
+/// A data block split into its entries and optional hash index.
+pub(crate) struct Block<'a> {
+    entries: &'a [u8],
+    buckets: Option<&'a [u8]>,
+}
+
+impl<'a> Block<'a> {
+    /// Splits `data` into entries and hash index when `hashed` is set.
+    pub fn new(data: &'a [u8], hashed: bool) -> io::Result<Self> {
+        if !hashed {
+            return Ok(Self {
+                entries: data,
+                buckets: None,
+            });
+        }
+
+        let trailer_start = data
+            .len()
+            .checked_sub(HASH_TRAILER_SIZE)
+            .ok_or_else(|| invalid_data("Block too short for hash index"))?;
+        let trailer = &data[trailer_start..];
+        let num_buckets = u32::from_le_bytes(trailer[0..4].try_into().unwrap()) as usize;
+        let entries_len = u32::from_le_bytes(trailer[4..8].try_into().unwrap()) as usize;
+        if entries_len + num_buckets * 2 != trailer_start {
+            return Err(invalid_data("Corrupt block hash index"));
+        }
+
+        Ok(Self {
+            entries: &data[..entries_len],
+            buckets: Some(&data[entries_len..trailer_start]),
+        })
+    }
+
+    /// Length of the entry region.
+    pub fn entries_len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> BlockIter<'a> {
+        BlockIter::new(self.entries)
+    }
+
+    /// Finds the entry for `key`, through the hash index when there is one.
+    pub fn get(&self, key: &str) -> io::Result<Option<BlockValue<'a>>> {
+        if let Some(buckets) = self.buckets
+            && !buckets.is_empty()
+        {
+            let bucket = (filter::hash(key.as_bytes()) % (buckets.len() / 2) as u64) as usize;
+            match u16::from_le_bytes([buckets[bucket * 2], buckets[bucket * 2 + 1]]) {
+                EMPTY_BUCKET => return Ok(None),
+                COLLISION_BUCKET => {}
+                offset => {
+                    let entry = self
+                        .entries
+                        .get(offset as usize..)
+                        .and_then(|rest| BlockIter::new(rest).next())
+                        .ok_or_else(|| invalid_data("Bad block hash index offset"))?;
+                    let (k, v) = entry?;
+                    return Ok((k == key).then_some(v));
+                }
+            }
+        }
+
+        for entry in self.iter() {
+            let (k, v) = entry?;
+            if k == key {
+                return Ok(Some(v));
+            } else if k > key {
+                // Passed it
+                break;
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Collects the keys of a data block as it is built and encodes its hash
+/// index trailer.
+#[derive(Default)]
+pub(crate) struct BlockHashIndexBuilder {
+    entries: Vec<(u64, usize)>,
+}
+
+impl BlockHashIndexBuilder {
+    pub fn add(&mut self, key: &str, offset: usize) {
+        self.entries.push((filter::hash(key.as_bytes()), offset));
+    }
+
+    /// Appends the trailer for the entries added so far to `block`, which
+    /// holds exactly those entries, and resets the builder.
+    pub fn finish(&mut self, block: &mut Vec<u8>) {
+        let entries_len = block.len();
+        let num_buckets = ((self.entries.len() as f64 * BUCKET_RATIO).ceil() as usize).max(1);
+
+        let mut buckets = vec![EMPTY_BUCKET; num_buckets];
+        for (hash, offset) in self.entries.drain(..) {
+            let bucket = &mut buckets[(hash % num_buckets as u64) as usize];
+            *bucket = if *bucket == EMPTY_BUCKET && offset < COLLISION_BUCKET as usize {
+                offset as u16
+            } else {
+                COLLISION_BUCKET
+            };
+        }
+
+        for bucket in buckets {
+            block.extend_from_slice(&bucket.to_le_bytes());
+        }
+        block.extend_from_slice(&(num_buckets as u32).to_le_bytes());
+        block.extend_from_slice(&(entries_len as u32).to_le_bytes());
+    }
+}
+
+/// Iterates over the `(key, value)` entries of an in-memory data block.
+pub(crate) struct BlockIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BlockIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Byte offset of the next entry within the block.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid_data("Truncated block entry"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn take_u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn take_str(&mut self, n: usize) -> io::Result<&'a str> {
+        std::str::from_utf8(self.take(n)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn read_entry(&mut self) -> io::Result<(&'a str, BlockValue<'a>)> {
+        let key_len = self.take_u32()?;
+        let key = self.take_str(key_len as usize)?;
+
+        let val_len = self.take_u32()?;
+        let val = match val_len {
+            TOMBSTONE => BlockValue::Tombstone,
+            BLOB_REF => {
+                let bytes = self.take(BLOB_POINTER_SIZE)?;
+                BlockValue::Blob(BlobPointer::decode(bytes.try_into().unwrap()))
+            }
+            len => BlockValue::Inline(self.take_str(len as usize)?),
+        };
+
+        Ok((key, val))
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = io::Result<(&'a str, BlockValue<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let entry = self.read_entry();
+        if entry.is_err() {
+            // Don't keep yielding garbage after a corrupt entry
+            self.pos = self.data.len();
+        }
+        Some(entry)
+    }
+}
@@ -2,16 +2,33 @@ use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use super::{BLOCK_SIZE, TOMBSTONE};
+use super::BLOCK_SIZE;
+use super::block::{BlockHashIndexBuilder, BlockValue, HASH_INDEX_BLOCK};
+use super::filter::{self, BloomFilter, PREFIX_FILTER_BLOCK, PrefixFilter};
+use super::format::{BlockHandle, Footer, encode_meta_index};
+use super::index::{PARTITIONED_INDEX_BLOCK, encode_full_entry, encode_handle_entry};
+use super::properties::{PROPERTIES_BLOCK, TableProperties};
+use crate::blob::{BlobPointer, BlobStore};
+use crate::prefix::PrefixExtractor;
+use crate::rate_limiter::RateLimiter;
 
-#[derive(Debug)]
 pub struct SSTableBuilder {
     file: File,
     block_buffer: Vec<u8>,
     index: BTreeMap<String, u64>, // StartKey -> Offset
     current_offset: u64,
     first_key_in_block: Option<String>,
+    properties: TableProperties,
+    index_partition_size: Option<usize>,
+    blob_store: Option<(Arc<BlobStore>, usize)>,
+    wrote_blobs: bool,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    prefix_hashes: Vec<u64>,
+    hash_index: Option<BlockHashIndexBuilder>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl SSTableBuilder {
@@ -21,6 +38,9 @@ impl SSTableBuilder {
             .write(true)
             .truncate(true)
             .open(path.as_ref())?;
+        let creation_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
 
         Ok(Self {
             file,
@@ -28,30 +48,86 @@ impl SSTableBuilder {
             index: BTreeMap::new(),
             current_offset: 0,
             first_key_in_block: None,
+            properties: TableProperties {
+                creation_time,
+                ..TableProperties::default()
+            },
+            index_partition_size: None,
+            blob_store: None,
+            wrote_blobs: false,
+            prefix_extractor: None,
+            prefix_hashes: Vec::new(),
+            hash_index: None,
+            rate_limiter: None,
         })
     }
 
     pub fn add(&mut self, key: &str, value: &str) -> io::Result<()> {
-        let val_len = value.len();
-        let entry_size = 4 + key.len() + 4 + val_len;
-
-        // If adding this entry would exceed block size (and buffer is not empty), flush first
-        if !self.block_buffer.is_empty() && self.block_buffer.len() + entry_size > BLOCK_SIZE {
-            self.flush_block()?;
+        if let Some((blobs, threshold)) = &self.blob_store
+            && value.len() >= *threshold
+        {
+            let pointer = blobs.put(value)?;
+            self.wrote_blobs = true;
+            return self.add_entry(key, BlockValue::Blob(pointer));
         }
 
-        if self.first_key_in_block.is_none() {
-            self.first_key_in_block = Some(key.to_string());
-        }
+        self.add_entry(key, BlockValue::Inline(value))
+    }
 
-        self.write_entry_to_buffer(key, Some(value));
+    /// Adds an entry whose value already lives in a blob file.
+    pub fn add_blob_ref(&mut self, key: &str, pointer: BlobPointer) -> io::Result<()> {
+        self.add_entry(key, BlockValue::Blob(pointer))
+    }
 
-        Ok(())
+    /// Records the range of write sequence numbers covered by this table.
+    pub fn set_sequence_range(&mut self, min: u64, max: u64) {
+        self.properties.min_sequence = min;
+        self.properties.max_sequence = max;
+    }
+
+    /// Records when the table's newest data was written, in microseconds
+    /// since the Unix epoch. Defaults to when the builder was created.
+    pub fn set_creation_time(&mut self, micros: u64) {
+        self.properties.creation_time = micros;
     }
 
     pub fn delete(&mut self, key: &str) -> io::Result<()> {
-        let entry_size = 4 + key.len() + 4; // val_len (4) + (0 bytes payload)
+        self.add_entry(key, BlockValue::Tombstone)
+    }
+
+    /// Stores values of at least `threshold` bytes in `blobs` and keeps only
+    /// a pointer to them in the table.
+    pub fn set_blob_store(&mut self, blobs: Arc<BlobStore>, threshold: usize) {
+        self.blob_store = Some((blobs, threshold));
+    }
 
+    /// Stores a bloom filter over the key prefixes produced by `extractor`.
+    pub fn set_prefix_extractor(&mut self, extractor: Arc<dyn PrefixExtractor>) {
+        self.prefix_extractor = Some(extractor);
+    }
+
+    /// Ends every data block with a hash index from key to entry offset, so
+    /// point lookups can go straight to the entry.
+    pub fn set_block_hash_index(&mut self, enabled: bool) {
+        self.hash_index = enabled.then(BlockHashIndexBuilder::default);
+    }
+
+    /// Makes every write to the file wait for its bytes from `limiter`.
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Bytes of data blocks written so far, including the block being built.
+    ///
+    /// The finished file adds the index and meta blocks on top of this.
+    pub fn file_size(&self) -> u64 {
+        self.current_offset + self.block_buffer.len() as u64
+    }
+
+    fn add_entry(&mut self, key: &str, value: BlockValue<'_>) -> io::Result<()> {
+        let entry_size = 4 + key.len() + value.encoded_len();
+
+        // If adding this entry would exceed block size (and buffer is not empty), flush first
         if !self.block_buffer.is_empty() && self.block_buffer.len() + entry_size > BLOCK_SIZE {
             self.flush_block()?;
         }
@@ -60,25 +136,56 @@ impl SSTableBuilder {
             self.first_key_in_block = Some(key.to_string());
         }
 
-        self.write_entry_to_buffer(key, None);
+        self.write_entry_to_buffer(key, value);
         Ok(())
     }
 
-    fn write_entry_to_buffer(&mut self, key: &str, value: Option<&str>) {
+    /// Writes a two-level index whose partitions hold about `bytes` of
+    /// index entries each, instead of a single index block.
+    pub fn set_index_partition_size(&mut self, bytes: usize) {
+        self.index_partition_size = Some(bytes);
+    }
+
+    fn write_entry_to_buffer(&mut self, key: &str, value: BlockValue<'_>) {
+        self.record_properties(key, value);
+        self.record_prefix(key);
+        if let Some(hash_index) = &mut self.hash_index {
+            hash_index.add(key, self.block_buffer.len());
+        }
+
         let key_len = key.len() as u32;
         self.block_buffer.extend_from_slice(&key_len.to_le_bytes());
         self.block_buffer.extend_from_slice(key.as_bytes());
+        value.encode(&mut self.block_buffer);
+    }
 
+    fn record_properties(&mut self, key: &str, value: BlockValue<'_>) {
+        let props = &mut self.properties;
+        if props.num_entries == 0 {
+            props.smallest_key = key.to_string();
+        }
+        props.largest_key = key.to_string();
+        props.num_entries += 1;
+        props.raw_key_bytes += key.len() as u64;
         match value {
-            Some(v) => {
-                let val_len = v.len() as u32;
-                self.block_buffer.extend_from_slice(&val_len.to_le_bytes());
-                self.block_buffer.extend_from_slice(v.as_bytes());
-            }
-            None => {
-                self.block_buffer
-                    .extend_from_slice(&TOMBSTONE.to_le_bytes());
+            BlockValue::Inline(v) => props.raw_value_bytes += v.len() as u64,
+            BlockValue::Blob(pointer) => {
+                props.raw_value_bytes += pointer.len as u64;
+                props.num_blob_refs += 1;
             }
+            BlockValue::Tombstone => props.num_tombstones += 1,
+        }
+    }
+
+    fn record_prefix(&mut self, key: &str) {
+        let Some(prefix) = self.prefix_extractor.as_ref().and_then(|e| e.prefix(key)) else {
+            return;
+        };
+
+        // Keys arrive sorted, so equal prefixes are adjacent
+        let h = filter::hash(prefix.as_bytes());
+        if self.prefix_hashes.last() != Some(&h) {
+            self.prefix_hashes.push(h);
         }
     }
 
@@ -92,7 +199,12 @@ impl SSTableBuilder {
             self.index.insert(key.clone(), self.current_offset);
         }
 
+        if let Some(hash_index) = &mut self.hash_index {
+            hash_index.finish(&mut self.block_buffer);
+        }
+
         // Write buffer to file
+        self.throttle(self.block_buffer.len());
         self.file.write_all(&self.block_buffer)?;
         self.current_offset += self.block_buffer.len() as u64;
 
@@ -106,20 +218,103 @@ impl SSTableBuilder {
     pub fn finish(mut self) -> io::Result<()> {
         self.flush_block()?;
 
-        let index_offset = self.current_offset;
+        // Values must be durable before any table points at them
+        if let (Some((blobs, _)), true) = (&self.blob_store, self.wrote_blobs) {
+            blobs.seal()?;
+        }
+
+        self.properties.num_data_blocks = self.index.len() as u64;
+        let mut meta_blocks = Vec::new();
 
         // Write index
-        for (key, offset) in &self.index {
-            let key_len = key.len() as u32;
-            self.file.write_all(&key_len.to_le_bytes())?;
-            self.file.write_all(key.as_bytes())?;
-            self.file.write_all(&offset.to_le_bytes())?;
+        let index = match self.index_partition_size {
+            None => {
+                let mut index_block = Vec::new();
+                for (key, offset) in &self.index {
+                    encode_full_entry(&mut index_block, key, *offset);
+                }
+                self.write_raw_block(&index_block)?
+            }
+            Some(partition_size) => {
+                let top_level = self.write_index_partitions(partition_size)?;
+                meta_blocks.push((PARTITIONED_INDEX_BLOCK, top_level));
+                top_level
+            }
+        };
+
+        // Write meta blocks
+        if self.hash_index.is_some() {
+            let handle = self.write_raw_block(&[])?;
+            meta_blocks.push((HASH_INDEX_BLOCK, handle));
         }
+        if let Some(extractor) = &self.prefix_extractor {
+            let prefix_filter = PrefixFilter {
+                extractor: extractor.name().to_string(),
+                filter: BloomFilter::build(&self.prefix_hashes),
+            };
+            let handle = self.write_raw_block(&prefix_filter.encode())?;
+            meta_blocks.push((PREFIX_FILTER_BLOCK, handle));
+        }
+        let properties_block = self.properties.encode();
+        let properties = self.write_raw_block(&properties_block)?;
+        meta_blocks.push((PROPERTIES_BLOCK, properties));
+        let meta_index = encode_meta_index(&meta_blocks);
+        let meta_index = self.write_raw_block(&meta_index)?;
 
-        // Write footer: index_offset (8 bytes)
-        self.file.write_all(&index_offset.to_le_bytes())?;
+        // Write footer
+        let footer = Footer { index, meta_index }.encode();
+        self.throttle(footer.len());
+        self.file.write_all(&footer)?;
 
         self.file.sync_all()?;
         Ok(())
     }
+
+    /// Writes the index as partitions of `(first key, block handle)` entries
+    /// followed by a top-level index over the partitions.
+    fn write_index_partitions(&mut self, partition_size: usize) -> io::Result<BlockHandle> {
+        let data_end = self.current_offset;
+        let index = std::mem::take(&mut self.index);
+        let mut entries = index.iter().peekable();
+
+        let mut top_level = Vec::new();
+        let mut partition = Vec::new();
+        let mut partition_first_key = None;
+
+        while let Some((key, &offset)) = entries.next() {
+            let end = entries.peek().map_or(data_end, |(_, off)| **off);
+            let handle = BlockHandle {
+                offset,
+                len: end - offset,
+            };
+            encode_handle_entry(&mut partition, key, handle);
+            partition_first_key.get_or_insert(key);
+
+            if partition.len() >= partition_size || entries.peek().is_none() {
+                let partition_handle = self.write_raw_block(&partition)?;
+                let first_key = partition_first_key.take().unwrap();
+                encode_handle_entry(&mut top_level, first_key, partition_handle);
+                partition.clear();
+            }
+        }
+
+        self.write_raw_block(&top_level)
+    }
+
+    fn write_raw_block(&mut self, data: &[u8]) -> io::Result<BlockHandle> {
+        let handle = BlockHandle {
+            offset: self.current_offset,
+            len: data.len() as u64,
+        };
+        self.throttle(data.len());
+        self.file.write_all(data)?;
+        self.current_offset += data.len() as u64;
+        Ok(handle)
+    }
+
+    fn throttle(&self, bytes: usize) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.request(bytes as u64);
+        }
+    }
 }
@@ -0,0 +1,55 @@
+use std::io;
+use std::path::Path;
+
+use super::SSTableBuilder;
+
+/// Writes an SSTable outside of any database, for bulk loading through
+/// [`Database::ingest_external_files`](crate::Database::ingest_external_files).
+///
+/// Keys must be added in strictly increasing order.
+pub struct SstFileWriter {
+    builder: SSTableBuilder,
+    last_key: Option<String>,
+}
+
+impl SstFileWriter {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            builder: SSTableBuilder::new(path)?,
+            last_key: None,
+        })
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.check_order(key)?;
+        self.builder.add(key, value)
+    }
+
+    /// Records a deletion that shadows `key` in older tables once ingested.
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.check_order(key)?;
+        self.builder.delete(key)
+    }
+
+    /// Writes the index and footer. Fails if no entries were added.
+    pub fn finish(self) -> io::Result<()> {
+        if self.last_key.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot write an SSTable with no entries",
+            ));
+        }
+        self.builder.finish()
+    }
+
+    fn check_order(&mut self, key: &str) -> io::Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| key <= last) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Key {:?} is not greater than the previous key", key),
+            ));
+        }
+        self.last_key = Some(key.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Bloom filters stored as SSTable meta blocks.
+
+use std::io;
+
+use super::format::{get_str, invalid_data, put_str};
+
+/// Meta block holding the name of the prefix extractor and a bloom filter
+/// over the prefixes of the table's keys.
+pub(crate) const PREFIX_FILTER_BLOCK: &str = "janql.filter.prefix";
+
+const BITS_PER_KEY: usize = 10;
+
+/// 64-bit FNV-1a. Filters are persisted, so the hash must never change.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u32,
+}
+
+impl BloomFilter {
+    pub fn build(hashes: &[u64]) -> Self {
+        let num_bits = (hashes.len() * BITS_PER_KEY).max(64);
+        let num_probes = ((BITS_PER_KEY as f64) * std::f64::consts::LN_2).round() as u32;
+        let mut filter = Self {
+            bits: vec![0; num_bits.div_ceil(8)],
+            num_probes,
+        };
+
+        for &h in hashes {
+            for bit in filter.probes(h) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.probes(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bit positions for `hash`, derived by double hashing.
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> + use<> {
+        let num_bits = (self.bits.len() * 8) as u64;
+        let h1 = hash as u32 as u64;
+        let h2 = hash >> 32;
+        (0..self.num_probes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+/// A prefix filter together with the extractor that produced it.
+#[derive(Debug, Clone)]
+pub(crate) struct PrefixFilter {
+    pub extractor: String,
+    pub filter: BloomFilter,
+}
+
+impl PrefixFilter {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_str(&mut buf, &self.extractor);
+        buf.push(self.filter.num_probes as u8);
+        buf.extend_from_slice(&self.filter.bits);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut cursor = io::Cursor::new(buf);
+        let extractor = get_str(&mut cursor)?;
+        let rest = &buf[cursor.position() as usize..];
+        let (&num_probes, bits) = rest
+            .split_first()
+            .ok_or_else(|| invalid_data("Truncated prefix filter"))?;
+        if bits.is_empty() {
+            return Err(invalid_data("Empty prefix filter"));
+        }
+
+        Ok(Self {
+            extractor,
+            filter: BloomFilter {
+                bits: bits.to_vec(),
+                num_probes: num_probes as u32,
+            },
+        })
+    }
+}
@@ -0,0 +1,115 @@
+//! On-disk layout shared by the SSTable builder and reader.
+//!
+//! ```text
+//! [data blocks][index block][meta blocks...][meta index][footer]
+//! ```
+//!
+//! The footer is fixed size and ends with `MAGIC`. Tables written before the
+//! footer existed end with a bare 8-byte index offset instead; readers treat
+//! any file whose last 8 bytes are not `MAGIC` as such a legacy table.
+
+use std::io::{self, Read};
+
+pub(crate) const MAGIC: u64 = 0x4a61_6e51_4c53_5354; // "JanQLSST"
+pub(crate) const FOOTER_SIZE: u64 = 5 * 8;
+pub(crate) const LEGACY_FOOTER_SIZE: u64 = 8;
+
+/// A `(offset, len)` pair pointing at a region of the table file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct BlockHandle {
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Footer {
+    pub index: BlockHandle,
+    pub meta_index: BlockHandle,
+}
+
+impl Footer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_SIZE as usize);
+        put_u64(&mut buf, self.index.offset);
+        put_u64(&mut buf, self.index.len);
+        put_u64(&mut buf, self.meta_index.offset);
+        put_u64(&mut buf, self.meta_index.len);
+        put_u64(&mut buf, MAGIC);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut cursor = io::Cursor::new(buf);
+        let index = BlockHandle {
+            offset: get_u64(&mut cursor)?,
+            len: get_u64(&mut cursor)?,
+        };
+        let meta_index = BlockHandle {
+            offset: get_u64(&mut cursor)?,
+            len: get_u64(&mut cursor)?,
+        };
+        if get_u64(&mut cursor)? != MAGIC {
+            return Err(invalid_data("Bad footer magic"));
+        }
+        Ok(Self { index, meta_index })
+    }
+}
+
+/// Encodes a list of named meta blocks.
+pub(crate) fn encode_meta_index(entries: &[(&str, BlockHandle)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, handle) in entries {
+        put_str(&mut buf, name);
+        put_u64(&mut buf, handle.offset);
+        put_u64(&mut buf, handle.len);
+    }
+    buf
+}
+
+pub(crate) fn decode_meta_index(buf: &[u8]) -> io::Result<Vec<(String, BlockHandle)>> {
+    let mut cursor = io::Cursor::new(buf);
+    let mut entries = Vec::new();
+    while (cursor.position() as usize) < buf.len() {
+        let name = get_str(&mut cursor)?;
+        let offset = get_u64(&mut cursor)?;
+        let len = get_u64(&mut cursor)?;
+        entries.push((name, BlockHandle { offset, len }));
+    }
+    Ok(entries)
+}
+
+pub(crate) fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+pub(crate) fn get_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn get_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn get_str(r: &mut impl Read) -> io::Result<String> {
+    let len = get_u32(r)?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
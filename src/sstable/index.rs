@@ -0,0 +1,206 @@
+//! Block index formats.
+//!
+//! A full index lists the first key and offset of every data block and is
+//! kept in memory for the lifetime of the reader. A partitioned index splits
+//! those entries into partitions written like data blocks; only the small
+//! top-level index over the partitions is pinned, and partitions are read on
+//! demand (through the block cache, when the reader has one).
+
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+
+use super::format::{BlockHandle, get_str, get_u32, get_u64, invalid_data, put_str, put_u64};
+
+/// Meta block marking a table whose footer points at a top-level index.
+pub(crate) const PARTITIONED_INDEX_BLOCK: &str = "janql.index.partitioned";
+
+pub(crate) enum Index {
+    /// First key of every data block -> block offset.
+    Full(BTreeMap<String, u64>),
+    /// First key of every index partition -> partition.
+    Partitioned(BTreeMap<String, BlockHandle>),
+}
+
+pub(crate) fn decode_full(buf: &[u8]) -> io::Result<BTreeMap<String, u64>> {
+    let mut index = BTreeMap::new();
+    let mut cursor = io::Cursor::new(buf);
+
+    while (cursor.position() as usize) < buf.len() {
+        let key = get_str(&mut cursor)?;
+        let offset = get_u64(&mut cursor)?;
+        index.insert(key, offset);
+    }
+
+    Ok(index)
+}
+
+pub(crate) fn encode_full_entry(buf: &mut Vec<u8>, key: &str, offset: u64) {
+    put_str(buf, key);
+    put_u64(buf, offset);
+}
+
+/// Entry format shared by index partitions and the top-level index.
+pub(crate) fn encode_handle_entry(buf: &mut Vec<u8>, key: &str, handle: BlockHandle) {
+    put_str(buf, key);
+    put_u64(buf, handle.offset);
+    put_u64(buf, handle.len);
+}
+
+pub(crate) fn decode_handle_entries(buf: &[u8]) -> io::Result<Vec<(String, BlockHandle)>> {
+    let mut entries = Vec::new();
+    let mut cursor = io::Cursor::new(buf);
+
+    while (cursor.position() as usize) < buf.len() {
+        let key = get_str(&mut cursor)?;
+        let offset = get_u64(&mut cursor)?;
+        let len = get_u64(&mut cursor)?;
+        entries.push((key, BlockHandle { offset, len }));
+    }
+
+    Ok(entries)
+}
+
+/// Finds the block that would hold `key` in a full index.
+pub(crate) fn locate_in_full(
+    index: &BTreeMap<String, u64>,
+    key: &str,
+    data_end: u64,
+) -> Option<BlockHandle> {
+    let offset = index
+        .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+        .next_back()
+        .map(|(_, &off)| off)?;
+    let end = index
+        .range::<str, _>((Bound::Excluded(key), Bound::Unbounded))
+        .next()
+        .map_or(data_end, |(_, &off)| off);
+
+    Some(BlockHandle {
+        offset,
+        len: end - offset,
+    })
+}
+
+/// Finds the index partition that would hold `key`.
+pub(crate) fn partition_for(top: &BTreeMap<String, BlockHandle>, key: &str) -> Option<BlockHandle> {
+    top.range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+        .next_back()
+        .map(|(_, &handle)| handle)
+}
+
+/// Finds the block that would hold `key` within an index partition.
+pub(crate) fn locate_in_partition(partition: &[u8], key: &str) -> io::Result<Option<BlockHandle>> {
+    let mut cursor = io::Cursor::new(partition);
+    let mut found = None;
+
+    while (cursor.position() as usize) < partition.len() {
+        let first_key_len = get_u32(&mut cursor)? as usize;
+        let start = cursor.position() as usize;
+        let first_key = partition
+            .get(start..start + first_key_len)
+            .ok_or_else(|| invalid_data("Truncated index entry"))?;
+        cursor.set_position((start + first_key_len) as u64);
+        let handle = BlockHandle {
+            offset: get_u64(&mut cursor)?,
+            len: get_u64(&mut cursor)?,
+        };
+
+        if first_key > key.as_bytes() {
+            break;
+        }
+        found = Some(handle);
+    }
+
+    Ok(found)
+}
+
+/// Data blocks of a full index whose key range overlaps `[start, end]`, where
+/// a missing `end` is unbounded.
+pub(crate) fn blocks_in_full(
+    index: &BTreeMap<String, u64>,
+    start: &str,
+    end: Option<&str>,
+    data_end: u64,
+) -> Vec<BlockHandle> {
+    let mut entries = entries_from(index, start).peekable();
+    let blocks = std::iter::from_fn(|| {
+        let (key, &offset) = entries.next()?;
+        let next = entries.peek().map_or(data_end, |&(_, &off)| off);
+        Some((
+            key.as_str(),
+            BlockHandle {
+                offset,
+                len: next - offset,
+            },
+        ))
+    });
+
+    overlapping(blocks, start, end)
+}
+
+/// Index partitions whose key range overlaps `[start, end]`.
+pub(crate) fn partitions_in_range(
+    top: &BTreeMap<String, BlockHandle>,
+    start: &str,
+    end: Option<&str>,
+) -> Vec<BlockHandle> {
+    overlapping(
+        entries_from(top, start).map(|(key, &handle)| (key.as_str(), handle)),
+        start,
+        end,
+    )
+}
+
+/// Data blocks listed in an index partition whose key range overlaps `[start, end]`.
+pub(crate) fn blocks_in_partition(
+    partition: &[u8],
+    start: &str,
+    end: Option<&str>,
+) -> io::Result<Vec<BlockHandle>> {
+    let entries = decode_handle_entries(partition)?;
+    Ok(overlapping(
+        entries.iter().map(|(key, handle)| (key.as_str(), *handle)),
+        start,
+        end,
+    ))
+}
+
+/// Entries starting with the one whose range would hold `key`.
+fn entries_from<'a, V>(
+    index: &'a BTreeMap<String, V>,
+    key: &str,
+) -> std::collections::btree_map::Range<'a, String, V> {
+    match index
+        .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+        .next_back()
+    {
+        Some((first, _)) => {
+            index.range::<str, _>((Bound::Included(first.as_str()), Bound::Unbounded))
+        }
+        None => index.range::<str, _>(..),
+    }
+}
+
+/// Filters consecutive `(first key, handle)` entries down to those whose
+/// range overlaps `[start, end]`. Each entry covers the keys up to the next
+/// entry's first key.
+fn overlapping<'a>(
+    entries: impl Iterator<Item = (&'a str, BlockHandle)>,
+    start: &str,
+    end: Option<&str>,
+) -> Vec<BlockHandle> {
+    let mut entries = entries.peekable();
+    let mut handles = Vec::new();
+
+    while let Some((first_key, handle)) = entries.next() {
+        if end.is_some_and(|end| first_key > end) {
+            break;
+        }
+        if entries.peek().is_none_or(|&(next_key, _)| next_key > start) {
+            handles.push(handle);
+        }
+    }
+
+    handles
+}
@@ -1,8 +1,28 @@
+pub(crate) mod block;
 pub mod builder;
+pub mod file_writer;
+pub(crate) mod filter;
+pub(crate) mod format;
+pub(crate) mod index;
+pub mod properties;
 pub mod reader;
 
 pub use builder::SSTableBuilder;
-pub use reader::{SSTableReader, SearchResult};
+pub use file_writer::SstFileWriter;
+pub use properties::TableProperties;
+pub use reader::{SSTableReader, ScanIterator, SearchResult, SearchResultRef};
+
+use crate::blob::BlobPointer;
 
 pub(crate) const BLOCK_SIZE: usize = 4 * 1024; // 4KB
 pub(crate) const TOMBSTONE: u32 = u32::MAX;
+pub(crate) const BLOB_REF: u32 = u32::MAX - 1;
+
+/// A value as stored in an SSTable entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableValue {
+    Inline(String),
+    /// The value lives in a blob file.
+    Blob(BlobPointer),
+    Tombstone,
+}
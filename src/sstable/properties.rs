@@ -0,0 +1,109 @@
+use std::io;
+
+use super::format::{get_str, get_u64, invalid_data, put_str, put_u64};
+
+pub(crate) const PROPERTIES_BLOCK: &str = "janql.properties";
+
+/// Summary statistics recorded in every SSTable when it is written.
+///
+/// Readers load these at open, so callers can rule a table out of a lookup or
+/// weigh it for compaction without touching its data blocks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    pub smallest_key: String,
+    pub largest_key: String,
+    pub num_entries: u64,
+    pub num_tombstones: u64,
+    pub raw_key_bytes: u64,
+    pub raw_value_bytes: u64,
+    pub min_sequence: u64,
+    pub max_sequence: u64,
+    pub num_data_blocks: u64,
+    pub num_blob_refs: u64,
+    /// Microseconds since the Unix epoch when the table's newest data was
+    /// written. Zero for tables written before this was recorded.
+    pub creation_time: u64,
+}
+
+impl TableProperties {
+    /// Returns `false` if `key` lies outside `[smallest_key, largest_key]`.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.num_entries > 0
+            && key >= self.smallest_key.as_str()
+            && key <= self.largest_key.as_str()
+    }
+
+    /// Returns `false` if `[start, end]` cannot intersect the table's key range.
+    pub fn overlaps(&self, start: &str, end: &str) -> bool {
+        self.num_entries > 0
+            && start <= self.largest_key.as_str()
+            && end >= self.smallest_key.as_str()
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        // Stored as (name, value) pairs so that new properties can be added
+        // without breaking readers of older tables.
+        let mut buf = Vec::new();
+        let mut put_prop = |name: &str, value: &[u8]| {
+            put_str(&mut buf, name);
+            put_u64(&mut buf, value.len() as u64);
+            buf.extend_from_slice(value);
+        };
+
+        put_prop("smallest_key", self.smallest_key.as_bytes());
+        put_prop("largest_key", self.largest_key.as_bytes());
+        put_prop("num_entries", &self.num_entries.to_le_bytes());
+        put_prop("num_tombstones", &self.num_tombstones.to_le_bytes());
+        put_prop("raw_key_bytes", &self.raw_key_bytes.to_le_bytes());
+        put_prop("raw_value_bytes", &self.raw_value_bytes.to_le_bytes());
+        put_prop("min_sequence", &self.min_sequence.to_le_bytes());
+        put_prop("max_sequence", &self.max_sequence.to_le_bytes());
+        put_prop("num_data_blocks", &self.num_data_blocks.to_le_bytes());
+        put_prop("num_blob_refs", &self.num_blob_refs.to_le_bytes());
+        put_prop("creation_time", &self.creation_time.to_le_bytes());
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut props = TableProperties::default();
+        let mut cursor = io::Cursor::new(buf);
+
+        while (cursor.position() as usize) < buf.len() {
+            let name = get_str(&mut cursor)?;
+            let len = get_u64(&mut cursor)? as usize;
+            let start = cursor.position() as usize;
+            let value = buf
+                .get(start..start + len)
+                .ok_or_else(|| invalid_data("Truncated property"))?;
+            cursor.set_position((start + len) as u64);
+
+            let as_u64 = || -> io::Result<u64> {
+                let bytes: [u8; 8] = value
+                    .try_into()
+                    .map_err(|_| invalid_data("Bad property width"))?;
+                Ok(u64::from_le_bytes(bytes))
+            };
+            let as_string = || {
+                String::from_utf8(value.to_vec())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            };
+
+            match name.as_str() {
+                "smallest_key" => props.smallest_key = as_string()?,
+                "largest_key" => props.largest_key = as_string()?,
+                "num_entries" => props.num_entries = as_u64()?,
+                "num_tombstones" => props.num_tombstones = as_u64()?,
+                "raw_key_bytes" => props.raw_key_bytes = as_u64()?,
+                "raw_value_bytes" => props.raw_value_bytes = as_u64()?,
+                "min_sequence" => props.min_sequence = as_u64()?,
+                "max_sequence" => props.max_sequence = as_u64()?,
+                "num_data_blocks" => props.num_data_blocks = as_u64()?,
+                "num_blob_refs" => props.num_blob_refs = as_u64()?,
+                "creation_time" => props.creation_time = as_u64()?,
+                _ => {} // Written by a newer version
+            }
+        }
+
+        Ok(props)
+    }
+}
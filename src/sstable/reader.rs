@@ -1,9 +1,25 @@
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::path::Path;
-
-use super::TOMBSTONE;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::TableValue;
+use super::block::{Block, BlockIter, BlockValue, HASH_INDEX_BLOCK};
+use super::filter::{self, PREFIX_FILTER_BLOCK, PrefixFilter};
+use super::format::{
+    BlockHandle, FOOTER_SIZE, Footer, LEGACY_FOOTER_SIZE, MAGIC, decode_meta_index, invalid_data,
+};
+use super::index::{self, Index, PARTITIONED_INDEX_BLOCK};
+use super::properties::{PROPERTIES_BLOCK, TableProperties};
+use crate::blob::{BlobPointer, BlobStore};
+use crate::cache::BlockCache;
+use crate::prefix::PrefixExtractor;
+use memmap2::Mmap;
+
+/// Source of the per-reader ids that key the block cache.
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum SearchResult {
@@ -12,17 +28,66 @@ pub enum SearchResult {
     Deleted,
 }
 
+/// Borrowed form of [`SearchResult`], pointing into a memory-mapped table.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SearchResultRef<'a> {
+    Found(&'a str),
+    /// The value lives in a blob file and cannot be borrowed from the table.
+    Blob(BlobPointer),
+    NotFound,
+    Deleted,
+}
+
+/// A finished SSTable opened for reads.
+///
+/// Lookups and scans take `&self`, so one reader can be shared by every
+/// thread reading the table.
 pub struct SSTableReader {
-    pub(crate) file: File,
-    pub index: BTreeMap<String, u64>,
+    file: Mutex<File>,
+    path: PathBuf,
+    index: Index,
+    data_end: u64,
+    properties: Option<TableProperties>,
+    file_id: u64,
+    cache: Option<Arc<BlockCache>>,
+    mmap: Option<Mmap>,
+    blob_store: Option<Arc<BlobStore>>,
+    global_sequence: Option<u64>,
+    level: u32,
+    file_size: u64,
+    prefix_filter: Option<PrefixFilter>,
+    hashed_blocks: bool,
 }
 
 impl SSTableReader {
     pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
-        let mut file = File::open(path)?;
+        Self::open(path, None, false)
+    }
+
+    /// Opens a reader whose data blocks are served through `cache`.
+    pub fn with_cache(path: impl AsRef<Path>, cache: Arc<BlockCache>) -> io::Result<Self> {
+        Self::open(path, Some(cache), false)
+    }
+
+    /// Opens a reader that maps the whole file into memory.
+    ///
+    /// Lookups and scans then decode entries straight from the mapping
+    /// without any `seek`/`read` calls, and [`get_ref`](Self::get_ref) can
+    /// hand out values without copying them.
+    pub fn with_mmap(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open(path, None, true)
+    }
+
+    fn open(
+        path: impl AsRef<Path>,
+        cache: Option<Arc<BlockCache>>,
+        mmap: bool,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
         let len = file.metadata()?.len();
 
-        if len < 8 {
+        if len < LEGACY_FOOTER_SIZE {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "File too short"));
         }
 
@@ -30,244 +95,500 @@ impl SSTableReader {
         file.seek(SeekFrom::End(-8))?;
         let mut buf = [0u8; 8];
         file.read_exact(&mut buf)?;
-        let index_offset = u64::from_le_bytes(buf);
+        let last_word = u64::from_le_bytes(buf);
+
+        let mut prefix_filter = None;
+        let mut hashed_blocks = false;
+        let (index_handle, properties, partitioned) = if last_word == MAGIC && len >= FOOTER_SIZE {
+            file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+            let mut footer_buf = vec![0u8; FOOTER_SIZE as usize];
+            file.read_exact(&mut footer_buf)?;
+            let footer = Footer::decode(&footer_buf)?;
+
+            let meta_index = decode_meta_index(&read_block(&mut file, footer.meta_index)?)?;
+            let mut properties = None;
+            let mut partitioned = false;
+            for (name, handle) in meta_index {
+                match name.as_str() {
+                    PROPERTIES_BLOCK => {
+                        let block = read_block(&mut file, handle)?;
+                        properties = Some(TableProperties::decode(&block)?);
+                    }
+                    PARTITIONED_INDEX_BLOCK => partitioned = true,
+                    HASH_INDEX_BLOCK => hashed_blocks = true,
+                    PREFIX_FILTER_BLOCK => {
+                        let block = read_block(&mut file, handle)?;
+                        prefix_filter = Some(PrefixFilter::decode(&block)?);
+                    }
+                    _ => {}
+                }
+            }
+            (footer.index, properties, partitioned)
+        } else {
+            // Legacy table: the footer is just the index offset
+            let index_handle = BlockHandle {
+                offset: last_word,
+                len: len - LEGACY_FOOTER_SIZE - last_word,
+            };
+            (index_handle, None, false)
+        };
 
         // Read index
-        file.seek(SeekFrom::Start(index_offset))?;
-        let mut index = BTreeMap::new();
-
-        // Read until we hit the footer (last 8 bytes)
-        let index_len = len - 8 - index_offset;
-
-        let mut index_data = vec![0u8; index_len as usize];
-        file.seek(SeekFrom::Start(index_offset))?;
-        file.read_exact(&mut index_data)?;
-
-        let mut cursor = std::io::Cursor::new(index_data);
-        while cursor.position() < index_len {
-            // Read key len
-            let mut len_buf = [0u8; 4];
-            if cursor.read_exact(&mut len_buf).is_err() {
-                break;
-            }
-            let key_len = u32::from_le_bytes(len_buf);
+        let index_data = read_block(&mut file, index_handle)?;
+        let (index, data_end) = if partitioned {
+            let top: BTreeMap<_, _> = index::decode_handle_entries(&index_data)?
+                .into_iter()
+                .collect();
+            // Partitions are written right after the last data block
+            let data_end = top
+                .values()
+                .map(|h| h.offset)
+                .min()
+                .unwrap_or(index_handle.offset);
+            (Index::Partitioned(top), data_end)
+        } else {
+            let index = index::decode_full(&index_data)?;
+            (Index::Full(index), index_handle.offset)
+        };
 
-            // Read key
-            let mut key_buf = vec![0u8; key_len as usize];
-            cursor.read_exact(&mut key_buf)?;
-            let key = String::from_utf8(key_buf)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // SAFETY: finished tables are never modified in place, only replaced
+        // and unlinked, which leaves existing mappings intact.
+        let mmap = if mmap {
+            Some(unsafe { Mmap::map(&file)? })
+        } else {
+            None
+        };
 
-            // Read offset
-            let mut off_buf = [0u8; 8];
-            cursor.read_exact(&mut off_buf)?;
-            let offset = u64::from_le_bytes(off_buf);
+        Ok(Self {
+            file: Mutex::new(file),
+            path,
+            index,
+            data_end,
+            properties,
+            file_id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+            cache,
+            mmap,
+            blob_store: None,
+            global_sequence: None,
+            level: 0,
+            file_size: len,
+            prefix_filter,
+            hashed_blocks,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-            index.insert(key, offset);
+    /// Resolves blob pointers found in this table through `blobs`.
+    pub fn set_blob_store(&mut self, blobs: Arc<BlobStore>) {
+        self.blob_store = Some(blobs);
+    }
+
+    /// Sequence number that covers every entry of an ingested table, in
+    /// place of the range recorded in its properties.
+    pub fn global_sequence(&self) -> Option<u64> {
+        self.global_sequence
+    }
+
+    pub(crate) fn set_global_sequence(&mut self, sequence: u64) {
+        self.global_sequence = Some(sequence);
+    }
+
+    /// Compaction level the database keeps the table in.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    pub(crate) fn set_level(&mut self, level: u32) {
+        self.level = level;
+    }
+
+    /// Size of the table file in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Table properties, or `None` for tables written before they were recorded.
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.properties.as_ref()
+    }
+
+    /// First key and offset of every data block, or `None` for a table with
+    /// a partitioned index, whose entries are only read on demand.
+    pub fn index(&self) -> Option<&BTreeMap<String, u64>> {
+        match &self.index {
+            Index::Full(index) => Some(index),
+            Index::Partitioned(_) => None,
         }
+    }
 
-        Ok(Self { file, index })
+    /// Number of data blocks in the table.
+    pub fn num_data_blocks(&self) -> usize {
+        match &self.index {
+            Index::Full(index) => index.len(),
+            Index::Partitioned(_) => self
+                .properties
+                .as_ref()
+                .map_or(0, |p| p.num_data_blocks as usize),
+        }
     }
 
-    pub fn get(&mut self, key: &str) -> io::Result<SearchResult> {
-        let block_offset = self
-            .index
-            .range(..=key.to_string())
-            .next_back()
-            .map(|(_, &off)| off);
+    /// Returns `false` only if the table is known not to contain `key`.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.properties.as_ref().is_none_or(|p| p.may_contain(key))
+    }
 
-        if let Some(offset) = block_offset {
-            self.search_in_block(offset, key)
-        } else {
-            Ok(SearchResult::NotFound)
+    /// Returns `false` only if the table is known to hold no keys in `[start, end]`.
+    pub fn overlaps(&self, start: &str, end: &str) -> bool {
+        self.properties
+            .as_ref()
+            .is_none_or(|p| p.overlaps(start, end))
+    }
+
+    /// Returns `false` only if the table's prefix filter rules out every key
+    /// starting with `query`.
+    ///
+    /// The filter is consulted only if it was written by an extractor of the
+    /// same name and `query` is long enough to have a prefix of its own.
+    pub fn may_contain_prefix(&self, extractor: &dyn PrefixExtractor, query: &str) -> bool {
+        let Some(prefix_filter) = &self.prefix_filter else {
+            return true;
+        };
+        if prefix_filter.extractor != extractor.name() {
+            return true;
+        }
+
+        match extractor.prefix(query) {
+            Some(prefix) => prefix_filter
+                .filter
+                .may_contain(filter::hash(prefix.as_bytes())),
+            None => true,
         }
     }
 
-    fn search_in_block(&mut self, offset: u64, key: &str) -> io::Result<SearchResult> {
-        self.file.seek(SeekFrom::Start(offset))?;
+    pub fn get(&self, key: &str) -> io::Result<SearchResult> {
+        if self.mmap.is_some() {
+            let res = match self.get_ref(key)? {
+                SearchResultRef::Found(v) => SearchResult::Found(v.to_string()),
+                SearchResultRef::Blob(pointer) => SearchResult::Found(self.read_blob(pointer)?),
+                SearchResultRef::NotFound => SearchResult::NotFound,
+                SearchResultRef::Deleted => SearchResult::Deleted,
+            };
+            return Ok(res);
+        }
 
-        loop {
-            // Read key len
-            let mut len_buf = [0u8; 4];
-            if self.file.read_exact(&mut len_buf).is_err() {
-                break;
-            }
-            let k_len = u32::from_le_bytes(len_buf);
-
-            // Read key
-            let mut k_buf = vec![0u8; k_len as usize];
-            self.file.read_exact(&mut k_buf)?;
-            let k = String::from_utf8(k_buf)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-            // Read val len
-            let mut v_len_buf = [0u8; 4];
-            self.file.read_exact(&mut v_len_buf)?;
-            let v_len = u32::from_le_bytes(v_len_buf);
-
-            if k == key {
-                if v_len == TOMBSTONE {
-                    return Ok(SearchResult::Deleted); // Tombstone explicitly found
-                }
-                let mut v_buf = vec![0u8; v_len as usize];
-                self.file.read_exact(&mut v_buf)?;
-                let v = String::from_utf8(v_buf)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                return Ok(SearchResult::Found(v));
-            } else if k > key.to_string() {
-                // Passed it
-                return Ok(SearchResult::NotFound);
-            }
+        match self.locate_block(key)? {
+            Some(handle) => self.search_in_block(handle, key),
+            None => Ok(SearchResult::NotFound),
+        }
+    }
+
+    /// Looks `key` up without copying the value out of the mapping.
+    ///
+    /// Only available on readers opened with [`with_mmap`](Self::with_mmap).
+    pub fn get_ref(&self, key: &str) -> io::Result<SearchResultRef<'_>> {
+        let Some(mmap) = &self.mmap else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Reader is not memory-mapped",
+            ));
+        };
+
+        match self.locate_block_mapped(mmap, key)? {
+            Some(handle) => search_block(slice(mmap, handle)?, self.hashed_blocks, key),
+            None => Ok(SearchResultRef::NotFound),
+        }
+    }
+
+    /// Finds the data block whose key range would hold `key`.
+    fn locate_block(&self, key: &str) -> io::Result<Option<BlockHandle>> {
+        let partition = match &self.index {
+            Index::Full(index) => return Ok(index::locate_in_full(index, key, self.data_end)),
+            Index::Partitioned(top) => match index::partition_for(top, key) {
+                Some(partition) => partition,
+                None => return Ok(None),
+            },
+        };
+
+        let partition = self.read_data_block(partition)?;
+        index::locate_in_partition(&partition, key)
+    }
+
+    fn locate_block_mapped(&self, mmap: &[u8], key: &str) -> io::Result<Option<BlockHandle>> {
+        match &self.index {
+            Index::Full(index) => Ok(index::locate_in_full(index, key, self.data_end)),
+            Index::Partitioned(top) => match index::partition_for(top, key) {
+                Some(partition) => index::locate_in_partition(slice(mmap, partition)?, key),
+                None => Ok(None),
+            },
+        }
+    }
 
-            // Skip value
-            if v_len != TOMBSTONE {
-                self.file.seek(SeekFrom::Current(v_len as i64))?;
+    /// Reads a data block, going through the block cache when there is one.
+    fn read_data_block(&self, handle: BlockHandle) -> io::Result<Arc<Vec<u8>>> {
+        let Some(cache) = &self.cache else {
+            return read_block(&mut self.file.lock().unwrap(), handle).map(Arc::new);
+        };
+
+        let cache_key = (self.file_id, handle.offset);
+        if let Some(block) = cache.get(cache_key) {
+            return Ok(block);
+        }
+
+        let block = Arc::new(read_block(&mut self.file.lock().unwrap(), handle)?);
+        cache.insert(cache_key, block.clone());
+        Ok(block)
+    }
+
+    fn search_in_block(&self, handle: BlockHandle, key: &str) -> io::Result<SearchResult> {
+        let block = self.read_data_block(handle)?;
+        Ok(match search_block(&block, self.hashed_blocks, key)? {
+            SearchResultRef::Found(v) => SearchResult::Found(v.to_string()),
+            SearchResultRef::Blob(pointer) => SearchResult::Found(self.read_blob(pointer)?),
+            SearchResultRef::NotFound => SearchResult::NotFound,
+            SearchResultRef::Deleted => SearchResult::Deleted,
+        })
+    }
+
+    fn read_blob(&self, pointer: BlobPointer) -> io::Result<String> {
+        match &self.blob_store {
+            Some(blobs) => blobs.get(pointer),
+            None => Err(io::Error::other(
+                "Table holds blob pointers but has no blob store",
+            )),
+        }
+    }
+
+    /// Iterates over the live entries with keys in `[start, end]`.
+    ///
+    /// Only the data blocks whose key range overlaps the bounds are read, one
+    /// at a time as the iterator advances, so the scan never strays into the
+    /// index or meta blocks and holds at most one block's entries in memory.
+    pub fn scan(&self, start: &str, end: &str) -> io::Result<ScanIterator<'_>> {
+        Ok(ScanIterator {
+            reader: self,
+            blocks: self.blocks_in_range(start, Some(end))?.into_iter(),
+            start: start.to_string(),
+            end: end.to_string(),
+            entries: Vec::new().into_iter(),
+        })
+    }
+
+    /// Data blocks whose key range overlaps `[start, end]`, in key order.
+    /// A missing `end` is unbounded.
+    fn blocks_in_range(&self, start: &str, end: Option<&str>) -> io::Result<Vec<BlockHandle>> {
+        let partitions = match &self.index {
+            Index::Full(index) => {
+                return Ok(index::blocks_in_full(index, start, end, self.data_end));
             }
+            Index::Partitioned(top) => index::partitions_in_range(top, start, end),
+        };
+
+        let mut blocks = Vec::new();
+        for partition in partitions {
+            blocks.extend(match &self.mmap {
+                Some(mmap) => index::blocks_in_partition(slice(mmap, partition)?, start, end)?,
+                None => index::blocks_in_partition(&self.read_data_block(partition)?, start, end)?,
+            });
+        }
+        Ok(blocks)
+    }
+}
+
+impl Drop for SSTableReader {
+    fn drop(&mut self) {
+        if let Some(cache) = &self.cache {
+            cache.evict_file(self.file_id);
         }
+    }
+}
+
+/// Live entries of a key range, returned by [`SSTableReader::scan`].
+pub struct ScanIterator<'a> {
+    reader: &'a SSTableReader,
+    blocks: std::vec::IntoIter<BlockHandle>,
+    start: String,
+    end: String,
+    /// Entries of the current block within the range, tombstones included.
+    entries: std::vec::IntoIter<(String, TableValue)>,
+}
+
+impl ScanIterator<'_> {
+    /// Reads the next overlapping data block, through the block cache when
+    /// the reader has one.
+    fn load_next_block(&mut self) -> io::Result<bool> {
+        let Some(handle) = self.blocks.next() else {
+            return Ok(false);
+        };
 
-        Ok(SearchResult::NotFound)
+        let reader = self.reader;
+        let mut entries = Vec::new();
+        match &reader.mmap {
+            Some(mmap) => {
+                let block = Block::new(slice(mmap, handle)?, reader.hashed_blocks)?;
+                collect_range(&block, &self.start, &self.end, &mut entries)?
+            }
+            None => {
+                let data = reader.read_data_block(handle)?;
+                let block = Block::new(&data, reader.hashed_blocks)?;
+                collect_range(&block, &self.start, &self.end, &mut entries)?
+            }
+        }
+        self.entries = entries.into_iter();
+        Ok(true)
     }
+}
 
-    pub fn scan(&mut self, start: &str, end: &str) -> io::Result<Vec<(String, String)>> {
-        let mut results = Vec::new();
-        let start_offset = self
-            .index
-            .range(..=start.to_string())
-            .next_back()
-            .map(|(_, &off)| off)
-            .unwrap_or(0);
-        self.file.seek(SeekFrom::Start(start_offset))?;
+impl Iterator for ScanIterator<'_> {
+    type Item = io::Result<(String, String)>;
 
+    fn next(&mut self) -> Option<Self::Item> {
         loop {
-            // Read key len
-            let mut len_buf = [0u8; 4];
-            if self.file.read_exact(&mut len_buf).is_err() {
-                break;
-            }
-            let k_len = u32::from_le_bytes(len_buf);
-
-            // Read key
-            let mut k_buf = vec![0u8; k_len as usize];
-            self.file.read_exact(&mut k_buf)?;
-            let k = String::from_utf8(k_buf)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-            // Read val len
-            let mut v_len_buf = [0u8; 4];
-            self.file.read_exact(&mut v_len_buf)?;
-            let v_len = u32::from_le_bytes(v_len_buf);
-
-            if k >= start.to_string() && k <= end.to_string() {
-                if v_len != TOMBSTONE {
-                    let mut v_buf = vec![0u8; v_len as usize];
-                    self.file.read_exact(&mut v_buf)?;
-                    let v = String::from_utf8(v_buf)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                    results.push((k, v));
-                }
-            } else if k > end.to_string() {
-                // Done
-                break;
-            } else {
-                // Skip value
-                if v_len != TOMBSTONE {
-                    self.file.seek(SeekFrom::Current(v_len as i64))?;
+            match self.entries.next() {
+                Some((key, TableValue::Inline(v))) => return Some(Ok((key, v))),
+                Some((key, TableValue::Blob(pointer))) => {
+                    return Some(self.reader.read_blob(pointer).map(|v| (key, v)));
                 }
+                Some((_, TableValue::Tombstone)) => {}
+                None => match self.load_next_block() {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(e) => {
+                        self.blocks = Vec::new().into_iter();
+                        return Some(Err(e));
+                    }
+                },
             }
         }
-
-        Ok(results)
     }
 }
 
 pub struct SSTableIterator {
     reader: SSTableReader,
-    current_offset: u64,
-    end_offset: u64,
+    blocks: std::vec::IntoIter<BlockHandle>,
+    /// Entries of the current block.
+    block: Vec<u8>,
+    pos: usize,
+    error: Option<io::Error>,
 }
 
 impl IntoIterator for SSTableReader {
-    type Item = io::Result<(String, Option<String>)>;
+    type Item = io::Result<(String, TableValue)>;
     type IntoIter = SSTableIterator;
 
-    fn into_iter(mut self) -> Self::IntoIter {
-        // Find the start of the index (end_offset for data)
-        let end_offset = (|| {
-            let len = self.file.metadata().ok()?.len();
-            if len < 8 {
-                return None;
-            }
-
-            self.file.seek(SeekFrom::End(-8)).ok()?;
-            let mut buf = [0u8; 8];
-            self.file.read_exact(&mut buf).ok()?;
-
-            Some(u64::from_le_bytes(buf))
-        })()
-        .unwrap_or(u64::MAX);
-
-        // Reset to start
-        let _ = self.file.seek(SeekFrom::Start(0));
+    fn into_iter(self) -> Self::IntoIter {
+        let (blocks, error) = match self.blocks_in_range("", None) {
+            Ok(blocks) => (blocks, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
 
         SSTableIterator {
             reader: self,
-            current_offset: 0,
-            end_offset,
+            blocks: blocks.into_iter(),
+            block: Vec::new(),
+            pos: 0,
+            error,
         }
     }
 }
 
-impl Iterator for SSTableIterator {
-    type Item = io::Result<(String, Option<String>)>;
+impl SSTableIterator {
+    /// Loads the next data block, bypassing the block cache so that a full
+    /// pass over the table doesn't evict hot blocks.
+    fn load_next_block(&mut self) -> io::Result<bool> {
+        let Some(handle) = self.blocks.next() else {
+            return Ok(false);
+        };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.end_offset == u64::MAX {
-            return None;
-        } // Invalid state
-        if self.current_offset >= self.end_offset {
-            return None;
-        }
+        self.block = match &self.reader.mmap {
+            Some(mmap) => slice(mmap, handle)?.to_vec(),
+            None => read_block(self.reader.file.get_mut().unwrap(), handle)?,
+        };
+        let entries_len = Block::new(&self.block, self.reader.hashed_blocks)?.entries_len();
+        self.block.truncate(entries_len);
+        self.pos = 0;
+        Ok(true)
+    }
+}
 
-        let mut len_buf = [0u8; 4];
-        if let Err(_) = self.reader.file.read_exact(&mut len_buf) {
-            return None;
-        }
-        let key_len = u32::from_le_bytes(len_buf);
+impl Iterator for SSTableIterator {
+    type Item = io::Result<(String, TableValue)>;
 
-        let mut key_buf = vec![0u8; key_len as usize];
-        if let Err(e) = self.reader.file.read_exact(&mut key_buf) {
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.blocks = Vec::new().into_iter();
             return Some(Err(e));
         }
-        let key = match String::from_utf8(key_buf) {
-            Ok(k) => k,
-            Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
-        };
 
-        let mut v_len_buf = [0u8; 4];
-        if let Err(e) = self.reader.file.read_exact(&mut v_len_buf) {
-            return Some(Err(e));
+        while self.pos >= self.block.len() {
+            match self.load_next_block() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    self.blocks = Vec::new().into_iter();
+                    self.block.clear();
+                    return Some(Err(e));
+                }
+            }
         }
-        let val_len = u32::from_le_bytes(v_len_buf);
 
-        let val = if val_len == TOMBSTONE {
-            None
-        } else {
-            let mut v_buf = vec![0u8; val_len as usize];
-            if let Err(e) = self.reader.file.read_exact(&mut v_buf) {
-                return Some(Err(e));
+        let mut iter = BlockIter::new(&self.block[self.pos..]);
+        let entry = iter.next()?.map(|(k, v)| (k.to_string(), v.to_owned()));
+        match &entry {
+            Ok(_) => self.pos += iter.position(),
+            // Don't keep yielding garbage after a corrupt entry
+            Err(_) => {
+                self.blocks = Vec::new().into_iter();
+                self.block.clear();
             }
-            match String::from_utf8(v_buf) {
-                Ok(v) => Some(v),
-                Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
-            }
-        };
+        }
+        Some(entry)
+    }
+}
 
-        self.current_offset = self
-            .reader
-            .file
-            .stream_position()
-            .unwrap_or(self.current_offset);
+fn search_block<'a>(data: &'a [u8], hashed: bool, key: &str) -> io::Result<SearchResultRef<'a>> {
+    Ok(match Block::new(data, hashed)?.get(key)? {
+        Some(BlockValue::Inline(v)) => SearchResultRef::Found(v),
+        Some(BlockValue::Blob(pointer)) => SearchResultRef::Blob(pointer),
+        Some(BlockValue::Tombstone) => SearchResultRef::Deleted, // Tombstone explicitly found
+        None => SearchResultRef::NotFound,
+    })
+}
 
-        Some(Ok((key, val)))
+/// Appends the entries of `block` with keys in `[start, end]`, tombstones included.
+fn collect_range(
+    block: &Block<'_>,
+    start: &str,
+    end: &str,
+    out: &mut Vec<(String, TableValue)>,
+) -> io::Result<()> {
+    for entry in block.iter() {
+        let (k, v) = entry?;
+        if k > end {
+            break;
+        }
+        if k >= start {
+            out.push((k.to_string(), v.to_owned()));
+        }
     }
+    Ok(())
+}
+
+/// The bytes of a block within a mapped table, or an error if a corrupt
+/// handle points past the end of the mapping.
+fn slice(mmap: &[u8], handle: BlockHandle) -> io::Result<&[u8]> {
+    handle
+        .offset
+        .checked_add(handle.len)
+        .and_then(|end| mmap.get(handle.offset as usize..end as usize))
+        .ok_or_else(|| invalid_data("Block handle past the end of the table"))
+}
+
+fn read_block(file: &mut File, handle: BlockHandle) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; handle.len as usize];
+    file.seek(SeekFrom::Start(handle.offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
 }
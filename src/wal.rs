@@ -1,138 +1,639 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, Read, Write};
-use std::path::Path;
+//! Write-ahead log.
+//!
+//! A log starts with an 8-byte magic followed by framed records:
+//!
+//! ```text
+//! [crc u32][len u32][type u8][payload: len bytes]
+//! ```
+//!
+//! The CRC covers the type byte and the payload. A set payload is
+//! `[sequence u64][timestamp u64][key_len u32][key][val_len u32][value]`, a
+//! delete payload is `[sequence u64][timestamp u64][key_len u32][key]`.
+//!
+//! Older logs are still read. Records of types 1 and 2 have the same payloads
+//! without sequence and timestamp, and logs written before records were
+//! framed have no magic and hold bare `[type u8][payload]` records.
+//!
+//! The log is split into numbered segments, `wal_<n>.log`. A new segment is
+//! started whenever the memtable is flushed, and older ones are deleted once
+//! the manifest records that their writes are in SSTables, or moved to an
+//! archive directory if one is configured. A `wal.log` left by an older
+//! version is read as segment 0.
+//!
+//! Restoring a database to an earlier point cuts the archived segments back
+//! to it, after saving whole copies of them in a `generation_<n>`
+//! subdirectory of the archive, which restores don't read from.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::options::WriteOptions;
 
 pub struct WAL {
     file: File,
-    path: std::path::PathBuf,
+    dir: PathBuf,
+    number: u64,
+    sync_mode: WalSyncMode,
+    unsynced: u64,
+    last_sync: Instant,
+    syncs: u64,
 }
 
 const OP_SET: u8 = 1;
 const OP_DEL: u8 = 2;
+const OP_SEQ_SET: u8 = 3;
+const OP_SEQ_DEL: u8 = 4;
+
+const WAL_MAGIC: u64 = 0x4a61_6e51_4c57_414c; // "JanQLWAL"
+const MAGIC_SIZE: usize = 8;
+const LEGACY_LOG_FILE: &str = "wal.log";
+const GENERATION_PREFIX: &str = "generation_";
+const RECORD_HEADER_SIZE: usize = 4 + 4 + 1;
+
+/// When appended records are forced to disk with `fsync`.
+///
+/// Every mode but `Disabled` hands each record to the OS as it is written,
+/// so a crash of the process alone loses nothing. The modes differ in what
+/// survives a power loss or OS crash: only records that have been synced,
+/// plus everything already flushed to SSTables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalSyncMode {
+    /// Sync every write before it returns. No acknowledged write is lost.
+    #[default]
+    Always,
+    /// Sync once `n` records have been written since the last sync. Up to
+    /// `n - 1` acknowledged records can be lost.
+    EveryN(u64),
+    /// Sync on a write once this long has passed since the last sync. Writes
+    /// made since then can be lost, including all writes before an idle
+    /// period until the next write or [`sync_wal`](crate::Database::sync_wal).
+    Interval(Duration),
+    /// Only sync on [`sync_wal`](crate::Database::sync_wal). Writes become
+    /// durable when called, or when the memtable is flushed to an SSTable.
+    OnFlushOnly,
+    /// Don't log writes at all. Anything not yet flushed to an SSTable is
+    /// lost if the process exits.
+    Disabled,
+}
+
+/// Counters for the log's `fsync` calls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalStats {
+    pub syncs: u64,
+    /// Records written since the last sync.
+    pub unsynced_records: u64,
+}
+
+/// A single write as logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    /// Zero for records written before records carried one.
+    pub sequence: u64,
+    /// Microseconds since the Unix epoch when the write was committed. Zero
+    /// for records written before records carried one.
+    pub timestamp: u64,
+    pub key: String,
+    /// `None` for a delete.
+    pub value: Option<String>,
+}
+
+/// How recovery treats WAL records that fail their checksum or are cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecoveryMode {
+    /// Drop a damaged last record, as left by a crash mid-write, and
+    /// truncate the log there. Damage anywhere else is an error.
+    #[default]
+    TolerateCorruptedTail,
+    /// Fail on any damaged record, including the last one.
+    AbsoluteConsistency,
+    /// Skip every damaged record that can be stepped over and recover the
+    /// rest. Only use this when losing some writes beats not opening at all.
+    SkipCorruptedRecords,
+}
+
+/// What WAL recovery found while replaying a log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub records_replayed: u64,
+    pub records_dropped: u64,
+    pub bytes_dropped: u64,
+}
+
+impl RecoveryReport {
+    pub(crate) fn add(&mut self, other: &RecoveryReport) {
+        self.records_replayed += other.records_replayed;
+        self.records_dropped += other.records_dropped;
+        self.bytes_dropped += other.bytes_dropped;
+    }
+}
 
 impl WAL {
-    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(path.as_ref())?;
+    /// Opens the log in `dir` with the default sync mode.
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_sync_mode(dir, WalSyncMode::default())
+    }
+
+    /// Opens the newest segment in `dir` for appending, or starts the first
+    /// one if there is none.
+    pub fn with_sync_mode(dir: impl AsRef<Path>, sync_mode: WalSyncMode) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let number = segments(&dir)?.last().map_or(1, |&(n, _)| n.max(1));
+        let file = open_segment(&dir, number)?;
 
         Ok(Self {
             file,
-            path: path.as_ref().to_path_buf(),
+            dir,
+            number,
+            sync_mode,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            syncs: 0,
         })
     }
 
-    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
-        self.file.write_all(&[OP_SET])?;
-        
-        let key_len = key.len() as u32;
-        self.file.write_all(&key_len.to_le_bytes())?;
-        self.file.write_all(key.as_bytes())?;
+    /// Number of the segment new records are appended to.
+    pub fn number(&self) -> u64 {
+        self.number
+    }
 
-        let val_len = value.len() as u32;
-        self.file.write_all(&val_len.to_le_bytes())?;
-        self.file.write_all(value.as_bytes())?;
-        
-        self.file.sync_data()?; // Ensure durability
-        Ok(())
+    /// Appends `records` with a single write, and syncs at most once for all
+    /// of them.
+    pub fn append(&mut self, records: &[WalRecord], options: &WriteOptions) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            encode(record, &mut buf);
+        }
+        self.write(&buf, records.len() as u64, options)
     }
 
-    pub fn del(&mut self, key: &str) -> io::Result<()> {
-        self.file.write_all(&[OP_DEL])?;
-        
-        let key_len = key.len() as u32;
-        self.file.write_all(&key_len.to_le_bytes())?;
-        self.file.write_all(key.as_bytes())?;
-        
-        self.file.sync_data()?;
+    /// Forces every record written so far to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.syncs += 1;
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
         Ok(())
     }
 
-    pub fn batch_set(&mut self, entries: &[(String, String)]) -> io::Result<()> {
-        for (key, value) in entries {
-            self.file.write_all(&[OP_SET])?;
-            
-            let key_len = key.len() as u32;
-            self.file.write_all(&key_len.to_le_bytes())?;
-            self.file.write_all(key.as_bytes())?;
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            syncs: self.syncs,
+            unsynced_records: self.unsynced,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8], records: u64, options: &WriteOptions) -> io::Result<()> {
+        if self.sync_mode == WalSyncMode::Disabled {
+            return Ok(());
+        }
+
+        self.file.write_all(buf)?;
+        self.unsynced += records;
 
-            let val_len = value.len() as u32;
-            self.file.write_all(&val_len.to_le_bytes())?;
-            self.file.write_all(value.as_bytes())?;
+        let sync = options.sync.unwrap_or(match self.sync_mode {
+            WalSyncMode::Always => true,
+            WalSyncMode::EveryN(n) => self.unsynced >= n,
+            WalSyncMode::Interval(interval) => self.last_sync.elapsed() >= interval,
+            WalSyncMode::OnFlushOnly | WalSyncMode::Disabled => false,
+        });
+        if sync {
+            self.sync()?;
         }
-        self.file.sync_data()?;
         Ok(())
     }
-    
-    pub fn clear(&mut self) -> io::Result<()> {
-        self.file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.path)?;
+
+    /// Syncs the current segment and starts the next one. The old segment
+    /// stays until `retire_segments_before` retires it.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+        self.file = open_segment(&self.dir, self.number + 1)?;
+        self.number += 1;
+
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+
         Ok(())
     }
 }
 
+/// Path of segment `number` in `dir`.
+pub fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    if number == 0 {
+        dir.join(LEGACY_LOG_FILE)
+    } else {
+        dir.join(format!("wal_{:06}.log", number))
+    }
+}
+
+/// The segments in `dir`, oldest first.
+pub(crate) fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let number = if name == LEGACY_LOG_FILE {
+            Some(0)
+        } else {
+            name.strip_prefix("wal_")
+                .and_then(|n| n.strip_suffix(".log"))
+                .and_then(|n| n.parse().ok())
+        };
+        if let Some(number) = number {
+            segments.push((number, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Retires every segment older than `number`: moves it into `archive` if
+/// given, or deletes it. The unsequenced legacy segment is always deleted.
+pub(crate) fn retire_segments_before(
+    dir: &Path,
+    number: u64,
+    archive: Option<&Path>,
+) -> io::Result<()> {
+    for (n, path) in segments(dir)? {
+        if n >= number {
+            continue;
+        }
+        match archive {
+            Some(archive) if n > 0 => {
+                fs::create_dir_all(archive)?;
+                let dest = segment_path(archive, n);
+                if fs::rename(&path, &dest).is_err() {
+                    // The archive may be on another file system
+                    fs::copy(&path, &dest)?;
+                    File::open(&dest)?.sync_all()?;
+                    fs::remove_file(&path)?;
+                }
+            }
+            _ => fs::remove_file(&path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Deletes archived segments last modified more than `ttl` ago, including
+/// those of older generations, whose directories go once they are empty.
+pub(crate) fn expire_archive(archive: &Path, ttl: Duration) -> io::Result<()> {
+    expire_segments(archive, ttl)?;
+    for (_, dir) in generations(archive)? {
+        expire_segments(&dir, ttl)?;
+        if fs::read_dir(&dir)?.next().is_none() {
+            fs::remove_dir(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn expire_segments(dir: &Path, ttl: Duration) -> io::Result<()> {
+    for (_, path) in segments(dir)? {
+        let modified = fs::metadata(&path)?.modified()?;
+        if modified.elapsed().is_ok_and(|age| age > ttl) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The `generation_<n>` directories in `archive`, oldest first.
+pub(crate) fn generations(archive: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(archive)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(GENERATION_PREFIX))
+            .and_then(|n| n.parse::<u64>().ok());
+        if let Some(number) = number
+            && path.is_dir()
+        {
+            generations.push((number, path));
+        }
+    }
+    generations.sort();
+    Ok(generations)
+}
+
+/// Drops the records after `sequence` from the archived segments, so that a
+/// database restored to `sequence` can log new writes under those numbers.
+///
+/// Every segment that loses records is first copied whole into a new
+/// generation directory, so the discarded history is kept rather than
+/// destroyed.
+pub(crate) fn trim_archive(archive: &Path, sequence: u64) -> io::Result<()> {
+    let mut generation: Option<PathBuf> = None;
+    for (number, path) in segments(archive)? {
+        let records = WALIterator::new(&path)?.collect::<io::Result<Vec<_>>>()?;
+        if records.iter().all(|r| r.sequence <= sequence) {
+            continue;
+        }
+
+        let dir = match &generation {
+            Some(dir) => dir,
+            None => generation.insert(new_generation(archive)?),
+        };
+        let copy = segment_path(dir, number);
+        fs::copy(&path, &copy)?;
+        File::open(&copy)?.sync_all()?;
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+
+        let mut buf = WAL_MAGIC.to_le_bytes().to_vec();
+        let mut kept = 0;
+        for record in records.iter().filter(|r| r.sequence <= sequence) {
+            encode(record, &mut buf);
+            kept += 1;
+        }
+        if kept == 0 {
+            fs::remove_file(&path)?;
+            continue;
+        }
+
+        let tmp_path = path.with_extension("log.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+    }
+    Ok(())
+}
+
+/// Creates the generation directory after the newest one in `archive`.
+fn new_generation(archive: &Path) -> io::Result<PathBuf> {
+    let number = generations(archive)?.last().map_or(1, |(n, _)| n + 1);
+    let dir = archive.join(format!("{}{:06}", GENERATION_PREFIX, number));
+    fs::create_dir(&dir)?;
+    #[cfg(unix)]
+    File::open(archive)?.sync_all()?;
+    Ok(dir)
+}
+
+/// Cuts a segment back to `len` bytes, dropping a damaged tail so that new
+/// records don't end up behind it.
+pub(crate) fn truncate_segment(path: &Path, len: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    if len < MAGIC_SIZE as u64 {
+        file.set_len(0)?;
+        file.write_all(&WAL_MAGIC.to_le_bytes())?;
+    } else {
+        file.set_len(len)?;
+    }
+    file.sync_data()
+}
+
+fn open_segment(dir: &Path, number: u64) -> io::Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, number))?;
+
+    if file.metadata()?.len() == 0 {
+        file.write_all(&WAL_MAGIC.to_le_bytes())?;
+        file.sync_data()?;
+    }
+    Ok(file)
+}
+
+fn encode(record: &WalRecord, buf: &mut Vec<u8>) {
+    let mut payload = Vec::with_capacity(24 + record.key.len());
+    payload.extend_from_slice(&record.sequence.to_le_bytes());
+    payload.extend_from_slice(&record.timestamp.to_le_bytes());
+    payload.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(record.key.as_bytes());
+
+    let record_type = match &record.value {
+        Some(value) => {
+            payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            payload.extend_from_slice(value.as_bytes());
+            OP_SEQ_SET
+        }
+        None => OP_SEQ_DEL,
+    };
+    buf.extend_from_slice(&frame(record_type, &payload));
+}
+
+fn frame(record_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(payload);
+
+    let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.push(record_type);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Why a record could not be read.
+enum Damage {
+    /// The log ends inside the record.
+    Truncated,
+    /// The record is complete but fails its checksum or doesn't decode.
+    /// Holds the offset just past it.
+    Corrupt(usize),
+}
+
 pub struct WALIterator {
-    reader: BufReader<File>,
+    data: Vec<u8>,
+    pos: usize,
+    legacy: bool,
+    mode: WalRecoveryMode,
+    valid_len: u64,
+    report: RecoveryReport,
+    done: bool,
 }
 
 impl WALIterator {
     pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
-        let file = File::open(path)?;
+        Self::with_recovery_mode(path, WalRecoveryMode::default())
+    }
+
+    pub fn with_recovery_mode(path: impl AsRef<Path>, mode: WalRecoveryMode) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let magic_len = data.len().min(MAGIC_SIZE);
+        let legacy = data[..magic_len] != WAL_MAGIC.to_le_bytes()[..magic_len];
+
+        // A log cut off inside its magic holds no records
+        let valid_len = if !legacy && data.len() < MAGIC_SIZE {
+            0
+        } else {
+            data.len() as u64
+        };
+
         Ok(Self {
-            reader: BufReader::new(file),
+            pos: if legacy { 0 } else { magic_len },
+            valid_len,
+            data,
+            legacy,
+            mode,
+            report: RecoveryReport::default(),
+            done: false,
         })
     }
+
+    /// Whether the log predates framed records.
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// Records replayed and dropped so far.
+    pub fn report(&self) -> &RecoveryReport {
+        &self.report
+    }
+
+    /// Length of the log without the damaged tail dropped during recovery.
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    fn read_record(&self) -> Result<(WalRecord, usize), Damage> {
+        if self.legacy {
+            return self.read_legacy_record();
+        }
+
+        let rest = &self.data[self.pos..];
+        if rest.len() < RECORD_HEADER_SIZE {
+            return Err(Damage::Truncated);
+        }
+        let crc = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        let record_type = rest[8];
+        let Some(payload) = rest.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len) else {
+            return Err(Damage::Truncated);
+        };
+        let end = self.pos + RECORD_HEADER_SIZE + len;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[record_type]);
+        hasher.update(payload);
+        if hasher.finalize() != crc {
+            return Err(Damage::Corrupt(end));
+        }
+
+        let mut cursor = payload;
+        let (sequence, timestamp) = match record_type {
+            OP_SEQ_SET | OP_SEQ_DEL => (
+                take_u64(&mut cursor).ok_or(Damage::Corrupt(end))?,
+                take_u64(&mut cursor).ok_or(Damage::Corrupt(end))?,
+            ),
+            _ => (0, 0),
+        };
+        let key = take_str(&mut cursor).ok_or(Damage::Corrupt(end))?;
+        let value = match record_type {
+            OP_SET | OP_SEQ_SET => Some(take_str(&mut cursor).ok_or(Damage::Corrupt(end))?),
+            OP_DEL | OP_SEQ_DEL => None,
+            _ => return Err(Damage::Corrupt(end)),
+        };
+        if !cursor.is_empty() {
+            return Err(Damage::Corrupt(end));
+        }
+
+        let record = WalRecord {
+            sequence,
+            timestamp,
+            key,
+            value,
+        };
+        Ok((record, end))
+    }
+
+    fn read_legacy_record(&self) -> Result<(WalRecord, usize), Damage> {
+        let mut cursor = &self.data[self.pos..];
+        let (&op, rest) = cursor.split_first().ok_or(Damage::Truncated)?;
+        cursor = rest;
+
+        // Without checksums the only damage we can tell apart is a short
+        // record at the end of the log
+        let key = take_str(&mut cursor).ok_or(Damage::Truncated)?;
+        let value = match op {
+            OP_SET => Some(take_str(&mut cursor).ok_or(Damage::Truncated)?),
+            OP_DEL => None,
+            _ => return Err(Damage::Corrupt(self.data.len())),
+        };
+
+        let record = WalRecord {
+            sequence: 0,
+            timestamp: 0,
+            key,
+            value,
+        };
+        Ok((record, self.data.len() - cursor.len()))
+    }
+
+    fn corruption(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Corrupted WAL record at offset {}", self.pos),
+        )
+    }
+
+    /// Drops everything from the current record on.
+    fn drop_tail(&mut self) {
+        self.report.records_dropped += 1;
+        self.report.bytes_dropped += (self.data.len() - self.pos) as u64;
+        self.valid_len = self.pos as u64;
+        self.done = true;
+    }
 }
 
 impl Iterator for WALIterator {
-    type Item = io::Result<(String, Option<String>)>;
+    type Item = io::Result<WalRecord>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut op_buf = [0u8; 1];
-        match self.reader.read_exact(&mut op_buf) {
-            Ok(_) => {},
-            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
-            Err(e) => return Some(Err(e)),
-        }
-        
-        let op = op_buf[0];
-        
-        // Read Key
-        let mut len_buf = [0u8; 4];
-        if let Err(e) = self.reader.read_exact(&mut len_buf) { return Some(Err(e)); }
-        let key_len = u32::from_le_bytes(len_buf);
-        
-        let mut key_buf = vec![0u8; key_len as usize];
-        if let Err(e) = self.reader.read_exact(&mut key_buf) { return Some(Err(e)); }
-        let key = match String::from_utf8(key_buf) {
-            Ok(k) => k,
-            Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
-        };
+        while !self.done && self.pos < self.data.len() {
+            let damage = match self.read_record() {
+                Ok((record, end)) => {
+                    self.pos = end;
+                    self.report.records_replayed += 1;
+                    return Some(Ok(record));
+                }
+                Err(damage) => damage,
+            };
 
-        match op {
-            OP_SET => {
-                // Read Value
-                if let Err(e) = self.reader.read_exact(&mut len_buf) { return Some(Err(e)); }
-                let val_len = u32::from_le_bytes(len_buf);
-                
-                let mut val_buf = vec![0u8; val_len as usize];
-                if let Err(e) = self.reader.read_exact(&mut val_buf) { return Some(Err(e)); }
-                let val = match String::from_utf8(val_buf) {
-                    Ok(v) => v,
-                    Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
-                };
-                
-                Some(Ok((key, Some(val))))
-            }
-            OP_DEL => {
-                Some(Ok((key, None)))
+            match (self.mode, damage) {
+                (WalRecoveryMode::AbsoluteConsistency, _) => {
+                    self.done = true;
+                    return Some(Err(self.corruption()));
+                }
+                (_, Damage::Truncated) => self.drop_tail(),
+                (_, Damage::Corrupt(end)) if end == self.data.len() => self.drop_tail(),
+                (WalRecoveryMode::SkipCorruptedRecords, Damage::Corrupt(end)) => {
+                    self.report.records_dropped += 1;
+                    self.report.bytes_dropped += (end - self.pos) as u64;
+                    self.pos = end;
+                }
+                (WalRecoveryMode::TolerateCorruptedTail, Damage::Corrupt(_)) => {
+                    self.done = true;
+                    return Some(Err(self.corruption()));
+                }
             }
-            _ => Some(Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid opcode"))),
         }
+
+        None
     }
 }
+
+fn take_str(cursor: &mut &[u8]) -> Option<String> {
+    let (len, rest) = cursor.split_first_chunk::<4>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    let bytes = rest.get(..len)?;
+    *cursor = &rest[len..];
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+fn take_u64(cursor: &mut &[u8]) -> Option<u64> {
+    let (bytes, rest) = cursor.split_first_chunk::<8>()?;
+    *cursor = rest;
+    Some(u64::from_le_bytes(*bytes))
+}
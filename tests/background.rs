@@ -0,0 +1,97 @@
+use janql::wal::WalSyncMode;
+use janql::{CompactionPolicy, Database, Options};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn test_reads_during_background_flushes_and_compactions() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("background.db");
+    let options = Options {
+        target_file_size: 256 * 1024,
+        ..Options::default()
+    };
+
+    let db = Database::open(&db_path, options.clone()).expect("Failed to open database");
+    db.set_compaction_policy(CompactionPolicy::Leveled {
+        l0_trigger: 2,
+        level_multiplier: 4,
+        base_level_size: 1024 * 1024,
+    });
+    for i in 0..1000 {
+        db.set(format!("stable/{:04}", i), format!("value{}", i));
+    }
+    db.flush();
+
+    // Enough writes to fill a few memtables, each flushed and compacted
+    // while the readers keep checking the keys written above
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    for i in (0..1000).step_by(37) {
+                        let value = db.get(&format!("stable/{:04}", i));
+                        assert_eq!(value, Some(format!("value{}", i)));
+                    }
+                    assert_eq!(db.get_by_prefix("stable/").len(), 1000);
+                }
+            });
+        }
+
+        for i in 0..12_000 {
+            db.set(format!("bulk/{:05}", i), "x".repeat(1000));
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+
+    db.wait_for_background_jobs()
+        .expect("Background job failed");
+    let tables = db.tables();
+    assert!(tables.iter().any(|t| t.level > 0));
+    assert!(tables.iter().filter(|t| t.level == 0).count() < 2);
+    assert_eq!(db.get("bulk/11999"), Some("x".repeat(1000)));
+    drop(db);
+
+    let db = Database::open(&db_path, options).expect("Failed to open database");
+    assert_eq!(db.get_by_prefix("bulk/").len(), 12_000);
+    assert_eq!(db.get("stable/0999"), Some("value999".to_string()));
+}
+
+#[test]
+fn test_stalled_writes_during_background_full_compactions() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let options = Options {
+        background_threads: 1,
+        compaction_check_interval: Duration::from_millis(1),
+        wal_sync_mode: WalSyncMode::Disabled,
+        ..Options::default()
+    };
+    let db = Arc::new(
+        Database::open(dir.path().join("stall.db"), options).expect("Failed to open database"),
+    );
+    db.set_compaction_policy(CompactionPolicy::Periodic(Duration::from_millis(1)));
+
+    // Writers stall on full memtables while the only background thread keeps
+    // starting full compactions, which flush the memtable first
+    let (done, finished) = mpsc::channel();
+    let writer = db.clone();
+    thread::spawn(move || {
+        for i in 0..60_000 {
+            writer.set(format!("key{:06}", i), "x".repeat(1000));
+        }
+        done.send(()).unwrap();
+    });
+    finished
+        .recv_timeout(Duration::from_secs(120))
+        .expect("Writes never finished");
+
+    // Periodic(1ms) would keep the background thread busy for good
+    db.set_compaction_policy(CompactionPolicy::Disabled);
+    db.wait_for_background_jobs()
+        .expect("Background job failed");
+    assert_eq!(db.get("key059999"), Some("x".repeat(1000)));
+}
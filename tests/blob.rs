@@ -0,0 +1,234 @@
+use janql::prefix::DelimitedPrefix;
+use janql::sstable::SSTableReader;
+use janql::{Database, Options};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use tempfile::TempDir;
+
+fn blob_options() -> Options {
+    Options {
+        blob_threshold: Some(1024),
+        ..Options::default()
+    }
+}
+
+fn blob_files(path: &Path) -> Vec<String> {
+    let mut files: Vec<_> = fs::read_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".blob"))
+        .collect();
+    files.sort();
+    files
+}
+
+fn table_paths(path: &Path) -> Vec<PathBuf> {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "sst"))
+        .collect()
+}
+
+fn table_bytes(path: &Path) -> u64 {
+    table_paths(path)
+        .iter()
+        .map(|p| fs::metadata(p).unwrap().len())
+        .sum()
+}
+
+/// Whether the table's meta index lists a block hash index.
+fn has_block_hash_index(table: &Path) -> bool {
+    let name = b"janql.block.hash_index";
+    fs::read(table)
+        .unwrap()
+        .windows(name.len())
+        .any(|w| w == name)
+}
+
+#[test]
+fn test_large_values_stored_in_blob_files() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("blob.db");
+
+    let db = Database::open(&db_path, blob_options()).expect("Failed to open database");
+    for i in 0..20 {
+        db.set(format!("big{:02}", i), format!("{:02}", i).repeat(50_000));
+    }
+    db.set("small".to_string(), "tiny".to_string());
+    db.flush();
+
+    assert_eq!(blob_files(&db_path).len(), 1);
+    assert!(
+        table_bytes(&db_path) < 10_000,
+        "Values should not be inlined"
+    );
+    assert_eq!(db.table_properties()[0].num_blob_refs, 20);
+
+    assert_eq!(db.get("big07"), Some("07".repeat(50_000)));
+    assert_eq!(db.get("small"), Some("tiny".to_string()));
+    assert_eq!(db.get_by_prefix("big1").len(), 10);
+    drop(db);
+
+    for mmap_reads in [false, true] {
+        let options = Options {
+            mmap_reads,
+            ..blob_options()
+        };
+        let loaded_db = Database::open(&db_path, options).expect("Failed to load database");
+        assert_eq!(loaded_db.get("big19"), Some("19".repeat(50_000)));
+        assert_eq!(loaded_db.get_by_prefix("big0")[3], "03".repeat(50_000));
+    }
+}
+
+#[test]
+fn test_compaction_moves_blob_pointers() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("blob_compact.db");
+
+    let db = Database::open(&db_path, blob_options()).expect("Failed to open database");
+    for i in 0..10 {
+        db.set(format!("k{}", i), "x".repeat(100_000));
+        db.flush();
+    }
+    let blobs_before = blob_files(&db_path);
+    assert_eq!(blobs_before.len(), 10);
+
+    db.compact().expect("Failed to compact");
+
+    // Every value is still live, so no blob file was rewritten
+    assert_eq!(blob_files(&db_path), blobs_before);
+    assert!(table_bytes(&db_path) < 10_000);
+    assert_eq!(db.get("k3"), Some("x".repeat(100_000)));
+}
+
+#[test]
+fn test_blob_garbage_collection() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("blob_gc.db");
+
+    let db = Database::open(&db_path, blob_options()).expect("Failed to open database");
+
+    // blob_000001: fully overwritten later
+    db.set("a".to_string(), "a".repeat(10_000));
+    db.set("b".to_string(), "b".repeat(10_000));
+    db.flush();
+
+    // blob_000002: three quarters overwritten later
+    for key in ["c", "d", "e", "f"] {
+        db.set(key.to_string(), key.repeat(10_000));
+    }
+    db.flush();
+
+    // blob_000003: only new values
+    for key in ["a", "b", "c", "d", "e"] {
+        db.set(key.to_string(), key.to_uppercase().repeat(10_000));
+    }
+    db.set("g".to_string(), "g".repeat(10_000));
+    db.flush();
+
+    assert_eq!(blob_files(&db_path).len(), 3);
+    db.compact().expect("Failed to compact");
+
+    // The fully overwritten file is dropped, the mostly dead one is
+    // rewritten into a new file and the live one is kept as-is
+    assert_eq!(
+        blob_files(&db_path),
+        vec![
+            "blob_000003.blob".to_string(),
+            "blob_000004.blob".to_string()
+        ]
+    );
+    assert_eq!(db.get("a"), Some("A".repeat(10_000)));
+    assert_eq!(db.get("e"), Some("E".repeat(10_000)));
+    assert_eq!(db.get("f"), Some("f".repeat(10_000)));
+    assert_eq!(db.get("g"), Some("g".repeat(10_000)));
+    drop(db);
+
+    let loaded_db = Database::open(&db_path, blob_options()).expect("Failed to load database");
+    for key in ["a", "b", "c", "d", "e"] {
+        assert_eq!(loaded_db.get(key), Some(key.to_uppercase().repeat(10_000)));
+    }
+    assert_eq!(loaded_db.get("f"), Some("f".repeat(10_000)));
+    assert_eq!(loaded_db.get("g"), Some("g".repeat(10_000)));
+    assert_eq!(loaded_db.get_by_prefix("").len(), 7);
+}
+
+#[test]
+fn test_blob_garbage_collection_keeps_table_filters() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("blob_gc_filters.db");
+    let extractor = Arc::new(DelimitedPrefix::new('/'));
+    let options = Options {
+        prefix_extractor: Some(extractor.clone()),
+        block_hash_index: true,
+        ..blob_options()
+    };
+
+    let db = Database::open(&db_path, options).expect("Failed to open database");
+    for key in ["t/a", "t/b", "t/c", "t/d"] {
+        db.set(key.to_string(), key.repeat(5_000));
+    }
+    db.flush();
+    for key in ["t/a", "t/b", "t/c"] {
+        db.set(key.to_string(), key.to_uppercase().repeat(5_000));
+    }
+    db.flush();
+
+    // Compaction leaves the first blob file mostly dead, so garbage
+    // collection rewrites the merged table pointing into it
+    db.compact().expect("Failed to compact");
+    assert_eq!(blob_files(&db_path).len(), 2);
+    assert_eq!(db.get("t/d"), Some("t/d".repeat(5_000)));
+
+    let tables = table_paths(&db_path);
+    assert_eq!(tables.len(), 1);
+    let reader = SSTableReader::new(&tables[0]).expect("Failed to open table");
+    assert!(reader.may_contain_prefix(extractor.as_ref(), "t/"));
+    assert!(!reader.may_contain_prefix(extractor.as_ref(), "u/"));
+    assert!(has_block_hash_index(&tables[0]));
+}
+
+#[test]
+fn test_blob_garbage_collection_during_reads() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("blob_gc_reads.db");
+    let db = Database::open(&db_path, blob_options()).expect("Failed to open database");
+    for i in 0..1_000 {
+        db.set(format!("key{:04}", i), "00".repeat(1_000));
+    }
+    db.flush();
+
+    // Every compaction leaves the previous blob file dead, while the readers
+    // may still be scanning the table pointing into it
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    assert_eq!(db.get_by_prefix("key").len(), 1_000);
+                    assert_eq!(db.get("key0999").map(|v| v.len()), Some(2_000));
+                }
+            });
+        }
+
+        for round in 1..6 {
+            for i in 0..1_000 {
+                db.set(
+                    format!("key{:04}", i),
+                    format!("{:02}", round).repeat(1_000),
+                );
+            }
+            db.flush();
+            db.compact().expect("Failed to compact");
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+
+    // Dead files are gone once no reader needs them
+    assert_eq!(db.get("key0999"), Some("05".repeat(1_000)));
+    assert_eq!(blob_files(&db_path).len(), 1);
+}
@@ -0,0 +1,78 @@
+use janql::cache::BlockCache;
+use janql::sstable::{SSTableBuilder, SSTableReader, SearchResult};
+use janql::{Database, Options};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+#[test]
+fn test_block_cache_evicts_least_recently_used() {
+    // Every key lands in the same shard, whose share of the capacity is 1KB
+    let cache = BlockCache::new(16 * 1024);
+    let block = || Arc::new(vec![0u8; 400]);
+
+    cache.insert((1, 0), block());
+    cache.insert((1, 1), block());
+    assert!(cache.get((1, 0)).is_some()); // (1, 1) is now the oldest
+    cache.insert((1, 2), block());
+
+    assert!(cache.get((1, 1)).is_none());
+    assert!(cache.get((1, 0)).is_some());
+    assert!(cache.get((1, 2)).is_some());
+
+    let stats = cache.stats();
+    assert_eq!(stats.hits, 3);
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.usage, 800);
+}
+
+#[test]
+fn test_block_cache_shared_by_readers() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+
+    let mut readers = Vec::new();
+    for name in ["a.sst", "b.sst"] {
+        let path = dir.path().join(name);
+        let mut builder = SSTableBuilder::new(&path).expect("Failed to create builder");
+        builder.add("key", name).expect("Failed to add");
+        builder.finish().expect("Failed to finish");
+        readers.push(SSTableReader::with_cache(&path, cache.clone()).expect("Failed to open"));
+    }
+
+    for _ in 0..3 {
+        for (reader, name) in readers.iter_mut().zip(["a.sst", "b.sst"]) {
+            let res = reader.get("key").expect("Failed to get");
+            assert_eq!(res, SearchResult::Found(name.to_string()));
+        }
+    }
+
+    let stats = cache.stats();
+    assert_eq!(stats.misses, 2);
+    assert_eq!(stats.hits, 4);
+
+    drop(readers);
+    assert_eq!(cache.stats().usage, 0);
+}
+
+#[test]
+fn test_database_block_cache_capacity() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("cache.db");
+    let options = Options {
+        block_cache_capacity: 64 * 1024,
+        ..Options::default()
+    };
+
+    let db = Database::open(&db_path, options).expect("Failed to open database");
+    db.set("hot".to_string(), "value".to_string());
+    db.flush();
+
+    for _ in 0..5 {
+        assert_eq!(db.get("hot"), Some("value".to_string()));
+    }
+
+    let stats = db.block_cache_stats();
+    assert_eq!(stats.capacity, 64 * 1024);
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.hits, 4);
+}
@@ -1,12 +1,15 @@
-use janql::{CompactionPolicy, Database};
+use janql::compaction::{CompactionFilter, FilterDecision};
+use janql::{CompactionPolicy, CompactionTriggers, Database, Options};
+use std::collections::BTreeMap;
 use std::fs;
+use std::sync::Arc;
 use tempfile::tempdir;
 
 #[test]
 fn test_compaction_basic() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("test_db");
-    let mut db = Database::new(&db_path);
+    let db = Database::new(&db_path);
 
     // 1. Write "key1" -> "val1", Flush (SSTable 1)
     db.set("key1".to_string(), "val1".to_string());
@@ -32,7 +35,7 @@ fn test_compaction_basic() {
                 .unwrap()
                 .path()
                 .extension()
-                .map_or(false, |ext| ext == "sst")
+                .is_some_and(|ext| ext == "sst")
         })
         .count();
     assert_eq!(count, 3);
@@ -52,7 +55,7 @@ fn test_compaction_basic() {
                 .unwrap()
                 .path()
                 .extension()
-                .map_or(false, |ext| ext == "sst")
+                .is_some_and(|ext| ext == "sst")
         })
         .count();
     assert_eq!(count_after, 1);
@@ -62,7 +65,7 @@ fn test_compaction_basic() {
 fn test_compaction_tombstones() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("test_db_tomb");
-    let mut db = Database::new(&db_path);
+    let db = Database::new(&db_path);
 
     // 1. Write "a" -> "1", Flush
     db.set("a".to_string(), "1".to_string());
@@ -87,7 +90,7 @@ fn test_compaction_tombstones() {
 fn test_compaction_mixed() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("test_db_mixed");
-    let mut db = Database::new(&db_path);
+    let db = Database::new(&db_path);
 
     db.set("k1".to_string(), "v1".to_string());
     db.flush();
@@ -117,7 +120,7 @@ fn test_compaction_mixed() {
 fn test_compaction_policy() {
     let dir = tempdir().unwrap();
     let db_path = dir.path().join("test_db_policy");
-    let mut db = Database::new(&db_path);
+    let db = Database::new(&db_path);
 
     // Set policy to 100ms
     db.set_compaction_policy(CompactionPolicy::Periodic(
@@ -141,7 +144,7 @@ fn test_compaction_policy() {
                     .unwrap()
                     .path()
                     .extension()
-                    .map_or(false, |ext| ext == "sst")
+                    .is_some_and(|ext| ext == "sst")
             })
             .count()
     };
@@ -152,8 +155,9 @@ fn test_compaction_policy() {
     std::thread::sleep(std::time::Duration::from_millis(150));
 
     // 3. Trigger op
-    // Triggers compaction
+    // Triggers compaction, which runs in the background
     db.set("k3".to_string(), "v3".to_string());
+    db.wait_for_background_jobs().unwrap();
 
     // Flush memtable to verify compaction merged everything (including k3's memtable if compact flushes it)
     // compact() calls flush_memtable(). So k3 is in an SSTable.
@@ -164,3 +168,394 @@ fn test_compaction_policy() {
     assert_eq!(db.get("k2"), Some("v2".to_string()));
     assert_eq!(db.get("k3"), Some("v3".to_string()));
 }
+
+#[test]
+fn test_leveled_compaction() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_leveled");
+    let options = Options {
+        target_file_size: 2 * 1024,
+        ..Options::default()
+    };
+    let policy = CompactionPolicy::Leveled {
+        l0_trigger: 2,
+        level_multiplier: 2,
+        base_level_size: 4 * 1024,
+    };
+
+    let db = Database::open(&db_path, options.clone()).unwrap();
+    db.set_compaction_policy(policy);
+
+    let mut expected = BTreeMap::new();
+    for round in 0..20 {
+        for i in 0..50 {
+            let key = format!("key{:04}", (i * 37 + round * 11) % 500);
+            if (i + round) % 7 == 0 {
+                db.del(&key);
+                expected.remove(&key);
+            } else {
+                let value = format!("value{}_{}", round, i);
+                db.set(key.clone(), value.clone());
+                expected.insert(key, value);
+            }
+        }
+        db.flush();
+    }
+    db.set("last".to_string(), "1".to_string());
+    db.wait_for_background_jobs().unwrap();
+    expected.insert("last".to_string(), "1".to_string());
+
+    let tables = db.tables();
+    assert!(tables.iter().filter(|t| t.level == 0).count() < 2);
+    let max_level = tables.iter().map(|t| t.level).max().unwrap();
+    assert!(max_level >= 2);
+
+    // Below level 0, every level is one sorted run
+    for pair in tables.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if a.level == b.level && a.level > 0 {
+            let (a, b) = (
+                a.properties.as_ref().unwrap(),
+                b.properties.as_ref().unwrap(),
+            );
+            assert!(a.largest_key < b.smallest_key);
+        }
+    }
+    // Only the bottommost level has nothing left for tombstones to shadow
+    for table in tables.iter().filter(|t| t.level == max_level) {
+        assert_eq!(table.properties.as_ref().unwrap().num_tombstones, 0);
+    }
+
+    for i in 0..500 {
+        let key = format!("key{:04}", i);
+        assert_eq!(db.get(&key), expected.get(&key).cloned(), "{}", key);
+    }
+    drop(db);
+
+    let db = Database::open(&db_path, options).unwrap();
+    assert_eq!(db.tables().len(), tables.len());
+    for (key, value) in &expected {
+        assert_eq!(db.get(key).as_ref(), Some(value));
+    }
+}
+
+#[test]
+fn test_size_tiered_compaction() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_size_tiered");
+    let db = Database::new(&db_path);
+
+    // One large table at the bottom
+    for i in 0..500 {
+        db.set(format!("key{:03}", i), "old".repeat(20));
+    }
+    db.flush();
+
+    db.set_compaction_policy(CompactionPolicy::SizeTiered {
+        min_threshold: 4,
+        max_threshold: 32,
+        bucket_ratio: 1.5,
+    });
+    for round in 0..4 {
+        for i in 0..10 {
+            db.set(format!("key{:03}", round * 10 + i), format!("new{}", round));
+        }
+        db.del(&format!("key{:03}", 100 + round));
+        db.flush();
+    }
+    // The next write finds a bucket of four small tables
+    db.set("last".to_string(), "1".to_string());
+    db.wait_for_background_jobs().unwrap();
+
+    let tables = db.tables();
+    assert_eq!(tables.len(), 2);
+    let (merged, large) = (
+        tables[0].properties.as_ref().unwrap(),
+        tables[1].properties.as_ref().unwrap(),
+    );
+    assert_eq!(merged.num_entries, 44);
+    // The large table still holds the deleted keys
+    assert_eq!(merged.num_tombstones, 4);
+    assert_eq!(large.num_entries, 500);
+
+    assert_eq!(db.get("key005"), Some("new0".to_string()));
+    assert_eq!(db.get("key035"), Some("new3".to_string()));
+    assert_eq!(db.get("key050"), Some("old".repeat(20)));
+    assert_eq!(db.get("key102"), None);
+}
+
+#[test]
+fn test_fifo_compaction() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_fifo");
+    let db = Database::new(&db_path);
+
+    for table in 0..8 {
+        for i in 0..100 {
+            db.set(format!("t{}_{:03}", table, i), "v".repeat(50));
+        }
+        db.flush();
+    }
+    let sizes: Vec<u64> = db.tables().iter().map(|t| t.file_size).collect();
+    let limit = sizes[..3].iter().sum::<u64>() + sizes[3] / 2;
+
+    db.set_compaction_policy(CompactionPolicy::Fifo {
+        max_total_size: Some(limit),
+        max_age: None,
+    });
+    db.set("last".to_string(), "1".to_string());
+    db.wait_for_background_jobs().unwrap();
+
+    // Only the three newest tables fit, and nothing was merged
+    assert_eq!(db.tables().len(), 3);
+    assert_eq!(db.get("t4_000"), None);
+    assert_eq!(db.get("t5_099"), Some("v".repeat(50)));
+    assert_eq!(db.get("t7_000"), Some("v".repeat(50)));
+
+    db.set_compaction_policy(CompactionPolicy::Fifo {
+        max_total_size: None,
+        max_age: Some(std::time::Duration::from_millis(50)),
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    db.flush();
+    db.set("after".to_string(), "1".to_string());
+    db.wait_for_background_jobs().unwrap();
+
+    // Only the table flushed just now is young enough to stay
+    assert_eq!(db.tables().len(), 1);
+    assert_eq!(db.get("t7_000"), None);
+    assert_eq!(db.get("last"), Some("1".to_string()));
+    assert_eq!(db.get("after"), Some("1".to_string()));
+}
+
+#[test]
+fn test_time_window_compaction() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_time_window");
+    let db = Database::new(&db_path);
+    let window = std::time::Duration::from_millis(50);
+    db.set_compaction_policy(CompactionPolicy::TimeWindow {
+        window,
+        min_threshold: 4,
+    });
+
+    let mut expected = BTreeMap::new();
+    for round in 0..12 {
+        for i in 0..20 {
+            let key = format!("key{:03}", (i * 7 + round * 3) % 60);
+            let value = format!("value{}", round);
+            db.set(key.clone(), value.clone());
+            expected.insert(key, value);
+        }
+        db.flush();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    db.set("last".to_string(), "1".to_string());
+    db.wait_for_background_jobs().unwrap();
+    expected.insert("last".to_string(), "1".to_string());
+
+    let tables = db.tables();
+    assert!(tables.len() < 12);
+    let windows: Vec<u128> = tables
+        .iter()
+        .map(|t| t.properties.as_ref().unwrap().creation_time as u128 / window.as_micros())
+        .collect();
+    // Newest first, and every window before the newest is a single table
+    assert!(
+        windows
+            .windows(2)
+            .all(|pair| pair[0] > pair[1] || pair[1] == windows[0])
+    );
+
+    for (key, value) in &expected {
+        assert_eq!(db.get(key).as_ref(), Some(value));
+    }
+}
+
+/// Drops soft-deleted records and upgrades values to the current format.
+#[derive(Debug)]
+struct SchemaFilter;
+
+impl CompactionFilter for SchemaFilter {
+    fn filter(&self, _level: u32, _key: &str, value: &str) -> FilterDecision {
+        if value == "deleted" {
+            FilterDecision::Remove
+        } else if let Some(rest) = value.strip_prefix("v1:") {
+            FilterDecision::ChangeValue(format!("v2:{}", rest))
+        } else {
+            FilterDecision::Keep
+        }
+    }
+}
+
+#[test]
+fn test_compaction_filter() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_filter");
+    let options = Options {
+        compaction_filter: Some(Arc::new(SchemaFilter)),
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).unwrap();
+
+    // An older table the merged tables below won't include
+    for i in 0..100 {
+        db.set(format!("key{:03}", i), format!("v1:old{}", i));
+    }
+    db.flush();
+
+    db.set_compaction_policy(CompactionPolicy::SizeTiered {
+        min_threshold: 2,
+        max_threshold: 2,
+        bucket_ratio: 1.5,
+    });
+    db.set("key000".to_string(), "deleted".to_string());
+    db.set("key001".to_string(), "v1:new".to_string());
+    db.flush();
+    db.set("key002".to_string(), "deleted".to_string());
+    db.set("key003".to_string(), "v2:new".to_string());
+    db.flush();
+    db.wait_for_background_jobs().unwrap();
+
+    // Removed keys stay deleted while the older table still holds them
+    let tables = db.tables();
+    assert_eq!(tables.len(), 2);
+    assert_eq!(tables[0].properties.as_ref().unwrap().num_tombstones, 2);
+    assert_eq!(db.get("key000"), None);
+    assert_eq!(db.get("key001"), Some("v2:new".to_string()));
+    assert_eq!(db.get("key002"), None);
+    assert_eq!(db.get("key003"), Some("v2:new".to_string()));
+    assert_eq!(db.get("key004"), Some("v1:old4".to_string()));
+
+    db.compact().unwrap();
+    let tables = db.tables();
+    assert_eq!(tables.len(), 1);
+    let properties = tables[0].properties.as_ref().unwrap();
+    assert_eq!(properties.num_entries, 98);
+    assert_eq!(properties.num_tombstones, 0);
+    assert_eq!(db.get("key000"), None);
+    assert_eq!(db.get("key004"), Some("v2:old4".to_string()));
+}
+
+#[test]
+fn test_compact_range() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_range");
+    let options = Options {
+        target_file_size: 4 * 1024,
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).unwrap();
+
+    for tenant in ["a", "b", "c"] {
+        for i in 0..200 {
+            db.set(format!("{}/{:03}", tenant, i), "v".repeat(40));
+        }
+    }
+    db.compact().unwrap();
+    let before = db.tables();
+    assert!(before.len() > 6);
+
+    for i in 0..200 {
+        db.del(&format!("b/{:03}", i));
+    }
+    db.compact_range("b/", "b/\u{10FFFF}").unwrap();
+
+    // Only the tables around tenant b were rewritten, and nothing is left
+    // of it, not even tombstones
+    let after = db.tables();
+    assert!(after.len() < before.len());
+    for table in &before {
+        let (smallest, largest) = {
+            let p = table.properties.as_ref().unwrap();
+            (p.smallest_key.as_str(), p.largest_key.as_str())
+        };
+        let untouched = after.iter().any(|t| t.name == table.name);
+        assert_eq!(untouched, largest < "b/" || smallest > "b/\u{10FFFF}");
+    }
+    for table in &after {
+        assert_eq!(table.properties.as_ref().unwrap().num_tombstones, 0);
+    }
+    assert_eq!(db.get_by_prefix("b/").len(), 0);
+    assert_eq!(db.get_by_prefix("a/").len(), 200);
+    assert_eq!(db.get_by_prefix("c/").len(), 200);
+}
+
+#[test]
+fn test_read_amplification_trigger_without_writes() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_read_amp");
+    let options = Options {
+        compaction_check_interval: std::time::Duration::from_millis(20),
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).unwrap();
+
+    // Five tables spanning the same range
+    for table in 0..5 {
+        db.set("a".to_string(), table.to_string());
+        db.set(format!("m{}", table), "1".to_string());
+        db.set("z".to_string(), table.to_string());
+        db.flush();
+    }
+    db.set_compaction_policy(CompactionPolicy::Triggered(CompactionTriggers {
+        max_read_amplification: Some(2.0),
+        max_tables: Some(10),
+        ..CompactionTriggers::default()
+    }));
+    db.wait_for_background_jobs().unwrap();
+    assert_eq!(db.tables().len(), 5);
+
+    // Each of these searches every table, and nothing is written afterwards
+    for _ in 0..10 {
+        assert_eq!(db.get("m0"), Some("1".to_string()));
+    }
+    assert_eq!(db.read_amplification(), 5.0);
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    db.wait_for_background_jobs().unwrap();
+
+    assert_eq!(db.tables().len(), 1);
+    assert_eq!(db.read_amplification(), 0.0);
+    assert_eq!(db.get("a"), Some("4".to_string()));
+    assert_eq!(db.get("m0"), Some("1".to_string()));
+}
+
+#[test]
+fn test_tombstone_ratio_trigger() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("test_db_tombstones");
+    let options = Options {
+        target_file_size: 4 * 1024,
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).unwrap();
+
+    for tenant in ["a", "b", "c"] {
+        for i in 0..200 {
+            db.set(format!("{}/{:03}", tenant, i), "v".repeat(40));
+        }
+    }
+    db.compact().unwrap();
+    let compacted = db.tables().len();
+
+    db.set_compaction_policy(CompactionPolicy::Triggered(CompactionTriggers {
+        max_tombstone_ratio: Some(0.5),
+        ..CompactionTriggers::default()
+    }));
+    for i in 0..200 {
+        db.del(&format!("b/{:03}", i));
+    }
+    db.flush();
+    db.wait_for_background_jobs().unwrap();
+
+    // The table of tombstones was merged away with tenant b's tables
+    let tables = db.tables();
+    assert!(tables.len() < compacted);
+    assert!(
+        tables
+            .iter()
+            .all(|t| t.level > 0 && t.properties.as_ref().unwrap().num_tombstones == 0)
+    );
+    assert_eq!(db.get_by_prefix("b/").len(), 0);
+    assert_eq!(db.get_by_prefix("c/").len(), 200);
+}
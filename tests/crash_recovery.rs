@@ -0,0 +1,110 @@
+use janql::compaction::CrashPoint;
+use janql::{CompactionPolicy, Database, Options};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn files_with_suffix(dir: &Path, suffix: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .expect("Failed to list database")
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(suffix))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_crash_during_compaction_swap() {
+    let points = [
+        (CrashPoint::TablesWritten, false),
+        (CrashPoint::TablesRenamed, false),
+        (CrashPoint::ManifestWritten, true),
+        (CrashPoint::InputDeleted, true),
+    ];
+
+    for (point, compacted) in points {
+        let dir = TempDir::new().expect("Failed to create temp dir");
+        let db_path = dir.path().join("crash.db");
+
+        let db = Database::open(&db_path, Options::default()).expect("Failed to open database");
+        let mut expected = BTreeMap::new();
+        for table in 0..3 {
+            for i in 0..100 {
+                let key = format!("key{:03}", (i * 7 + table * 13) % 150);
+                if i % 9 == table {
+                    db.del(&key);
+                    expected.remove(&key);
+                } else {
+                    let value = format!("value{}_{}", table, i);
+                    db.set(key.clone(), value.clone());
+                    expected.insert(key, value);
+                }
+            }
+            db.flush();
+        }
+        let before = files_with_suffix(&db_path, ".sst");
+        assert_eq!(before.len(), 3);
+        drop(db);
+
+        let options = Options {
+            crash_point: Some(point),
+            ..Options::default()
+        };
+        let db = Database::open(&db_path, options).expect("Failed to open database");
+        assert!(db.compact().is_err(), "{:?}", point);
+        drop(db);
+
+        let left = files_with_suffix(&db_path, ".sst.tmp");
+        assert_eq!(left.len(), (point == CrashPoint::TablesWritten) as usize);
+
+        // Reopening keeps either the inputs or the output, never both
+        let db = Database::open(&db_path, Options::default()).expect("Failed to open database");
+        let tables: Vec<String> = db.tables().into_iter().map(|t| t.name).collect();
+        let mut names = tables.clone();
+        names.sort();
+        assert_eq!(files_with_suffix(&db_path, ".sst"), names, "{:?}", point);
+        assert!(files_with_suffix(&db_path, ".sst.tmp").is_empty());
+        if compacted {
+            assert_eq!(tables.len(), 1, "{:?}", point);
+        } else {
+            assert_eq!(names, before, "{:?}", point);
+        }
+
+        for i in 0..150 {
+            let key = format!("key{:03}", i);
+            assert_eq!(db.get(&key), expected.get(&key).cloned(), "{:?}", point);
+        }
+    }
+}
+
+#[test]
+fn test_failed_background_compactions_keep_writes_going() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let options = Options {
+        background_threads: 1,
+        crash_point: Some(CrashPoint::ManifestWritten),
+        ..Options::default()
+    };
+    let db =
+        Database::open(dir.path().join("failing.db"), options).expect("Failed to open database");
+    db.set_compaction_policy(CompactionPolicy::Leveled {
+        l0_trigger: 2,
+        level_multiplier: 4,
+        base_level_size: 1024 * 1024,
+    });
+
+    // Enough memtables for a few flushes, each followed by a compaction that
+    // fails
+    for i in 0..16_000 {
+        db.set(format!("key{:05}", i), "x".repeat(1000));
+    }
+
+    // The error is reported once, and writes and reads carry on
+    assert!(db.wait_for_background_jobs().is_err());
+    assert!(db.wait_for_background_jobs().is_ok());
+    db.set("after".to_string(), "1".to_string());
+    assert_eq!(db.get("after"), Some("1".to_string()));
+    assert_eq!(db.get("key15999"), Some("x".repeat(1000)));
+}
@@ -0,0 +1,135 @@
+use janql::{Database, Options, SstFileWriter};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn write_external(path: &Path, entries: &[(&str, Option<&str>)]) -> PathBuf {
+    let mut writer = SstFileWriter::create(path).expect("Failed to create writer");
+    for (key, value) in entries {
+        match value {
+            Some(v) => writer.put(key, v).expect("Failed to put"),
+            None => writer.delete(key).expect("Failed to delete"),
+        }
+    }
+    writer.finish().expect("Failed to finish");
+    path.to_path_buf()
+}
+
+#[test]
+fn test_ingest_external_files() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("ingest.db");
+
+    let db = Database::open(&db_path, Options::default()).expect("Failed to open database");
+    db.set("a".to_string(), "old".to_string());
+    db.set("c".to_string(), "old".to_string());
+    db.flush();
+    db.set("e".to_string(), "memtable".to_string());
+    db.set("z".to_string(), "memtable".to_string());
+
+    let files = [
+        write_external(
+            &dir.path().join("second.sst"),
+            &[("d", Some("ingested")), ("e", Some("ingested"))],
+        ),
+        write_external(
+            &dir.path().join("first.sst"),
+            &[
+                ("a", Some("ingested")),
+                ("b", Some("ingested")),
+                ("c", None),
+            ],
+        ),
+    ];
+    db.ingest_external_files(&files)
+        .expect("Failed to ingest files");
+
+    // Ingested entries shadow both older tables and the memtable
+    assert_eq!(db.get("a"), Some("ingested".to_string()));
+    assert_eq!(db.get("b"), Some("ingested".to_string()));
+    assert_eq!(db.get("c"), None);
+    assert_eq!(db.get("e"), Some("ingested".to_string()));
+    assert_eq!(db.get("z"), Some("memtable".to_string()));
+
+    // Later writes shadow the ingested entries
+    db.set("d".to_string(), "new".to_string());
+    drop(db);
+
+    let loaded_db =
+        Database::open(&db_path, Options::default()).expect("Failed to load database");
+    assert_eq!(loaded_db.get("a"), Some("ingested".to_string()));
+    assert_eq!(loaded_db.get("c"), None);
+    assert_eq!(loaded_db.get("d"), Some("new".to_string()));
+    assert_eq!(loaded_db.get("e"), Some("ingested".to_string()));
+
+    loaded_db.compact().expect("Failed to compact");
+    assert_eq!(
+        loaded_db.get_by_prefix(""),
+        vec!["ingested", "ingested", "new", "ingested", "memtable"]
+    );
+
+    // The caller's files are copied, not moved
+    assert!(files.iter().all(|f| f.exists()));
+}
+
+#[test]
+fn test_ingest_rejects_invalid_files() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("ingest_invalid.db");
+    let db = Database::open(&db_path, Options::default()).expect("Failed to open database");
+
+    let mut writer =
+        SstFileWriter::create(dir.path().join("unsorted.sst")).expect("Failed to create writer");
+    writer.put("b", "1").unwrap();
+    assert_eq!(
+        writer.put("a", "2").unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        writer.put("b", "2").unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    let empty = SstFileWriter::create(dir.path().join("empty.sst")).unwrap();
+    assert_eq!(empty.finish().unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let files = [
+        write_external(
+            &dir.path().join("x.sst"),
+            &[("a", Some("1")), ("m", Some("1"))],
+        ),
+        write_external(
+            &dir.path().join("y.sst"),
+            &[("k", Some("2")), ("z", Some("2"))],
+        ),
+    ];
+    let err = db.ingest_external_files(&files).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // Nothing was added
+    assert_eq!(db.get("a"), None);
+    assert!(db.table_properties().is_empty());
+}
+
+#[test]
+fn test_reopen_after_ingest_keeps_sequence() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("ingest_sequence.db");
+
+    let db = Database::open(&db_path, Options::default()).expect("Failed to open database");
+    db.set("a".to_string(), "1".to_string());
+    db.set("b".to_string(), "1".to_string());
+    let file = write_external(&dir.path().join("x.sst"), &[("x", Some("ingested"))]);
+    db.ingest_external_files(&[file])
+        .expect("Failed to ingest files");
+    assert_eq!(db.last_sequence(), 3);
+    drop(db);
+
+    // The ingested table took sequence 3 without a WAL record
+    let db = Database::open(&db_path, Options::default()).expect("Failed to open database");
+    assert_eq!(db.last_sequence(), 3);
+    db.set("x".to_string(), "new".to_string());
+    assert_eq!(db.last_sequence(), 4);
+    assert_eq!(db.get("x"), Some("new".to_string()));
+    assert_eq!(db.get("a"), Some("1".to_string()));
+}
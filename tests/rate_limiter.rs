@@ -0,0 +1,65 @@
+use janql::rate_limiter::RateLimiter;
+use janql::{Database, Options};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+#[test]
+fn test_rate_limiter_throttles_requests() {
+    let limiter = RateLimiter::new(1024 * 1024);
+
+    // The first 100ms worth goes through at once, the rest at the rate
+    let start = Instant::now();
+    for _ in 0..30 {
+        limiter.request(10 * 1024);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(180));
+    assert_eq!(limiter.total_bytes(), 300 * 1024);
+}
+
+#[test]
+fn test_rate_limiter_auto_tune() {
+    let limiter = RateLimiter::auto_tuned(100 * 1024, 1024 * 1024, Duration::from_millis(5));
+    assert_eq!(limiter.bytes_per_sec(), 1024 * 1024);
+
+    // Slow reads back off, down to the minimum
+    for _ in 0..12 {
+        thread::sleep(Duration::from_millis(110));
+        limiter.record_latency(Duration::from_millis(20));
+    }
+    assert_eq!(limiter.bytes_per_sec(), 100 * 1024);
+
+    // Fast reads let it recover
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(110));
+        limiter.record_latency(Duration::from_micros(50));
+    }
+    assert!(limiter.bytes_per_sec() > 100 * 1024);
+}
+
+#[test]
+fn test_rate_limiter_applies_to_background_jobs() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let limiter = Arc::new(RateLimiter::new(64 * 1024 * 1024));
+    let options = Options {
+        rate_limiter: Some(limiter.clone()),
+        ..Options::default()
+    };
+    let db =
+        Database::open(dir.path().join("limited.db"), options).expect("Failed to open database");
+
+    // Flushes run by the caller aren't throttled
+    db.set("key".to_string(), "value".to_string());
+    db.flush();
+    assert_eq!(limiter.total_bytes(), 0);
+
+    // Filling the memtable hands its flush to a background thread
+    for i in 0..5000 {
+        db.set(format!("bulk/{:05}", i), "x".repeat(1000));
+    }
+    db.wait_for_background_jobs()
+        .expect("Background job failed");
+    assert!(limiter.total_bytes() > 4 * 1024 * 1024);
+    assert_eq!(db.get("bulk/04999"), Some("x".repeat(1000)));
+}
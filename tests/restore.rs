@@ -0,0 +1,240 @@
+use janql::prefix::DelimitedPrefix;
+use janql::sstable::SSTableReader;
+use janql::wal::segment_path;
+use janql::{Database, Options, RestoreTarget};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+fn open(path: &Path, archive: &Path) -> Database {
+    let options = Options {
+        wal_archive_dir: Some(archive.to_path_buf()),
+        ..Options::default()
+    };
+    Database::open(path, options).expect("Failed to open database")
+}
+
+#[test]
+fn test_restore_to_sequence_and_timestamp() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("pitr.db");
+    let archive = dir.path().join("archive");
+    let backup = dir.path().join("backup");
+
+    let db = open(&db_path, &archive);
+    db.set("a".to_string(), "1".to_string());
+    db.set("b".to_string(), "1".to_string());
+    db.checkpoint(&backup).expect("Failed to checkpoint");
+    assert!(segment_path(&archive, 1).exists());
+
+    db.set("a".to_string(), "2".to_string()); // 3
+    db.flush();
+    db.set("a".to_string(), "3".to_string()); // 4
+    thread::sleep(Duration::from_millis(2));
+    let before_delete = SystemTime::now();
+    thread::sleep(Duration::from_millis(2));
+
+    // The bad deploy
+    db.del("b"); // 5
+    db.flush();
+    db.set("c".to_string(), "1".to_string()); // 6, still in the live WAL
+
+    db.restore_to(&backup, RestoreTarget::Timestamp(before_delete))
+        .expect("Failed to restore");
+    assert_eq!(db.last_sequence(), 4);
+    assert_eq!(db.get("a"), Some("3".to_string()));
+    assert_eq!(db.get("b"), Some("1".to_string()));
+    assert_eq!(db.get("c"), None);
+
+    db.restore_to(&backup, RestoreTarget::Sequence(3))
+        .expect("Failed to restore");
+    assert_eq!(db.last_sequence(), 3);
+    assert_eq!(db.get("a"), Some("2".to_string()));
+
+    // New writes take over the sequence numbers of the discarded ones
+    db.set("d".to_string(), "1".to_string());
+    assert_eq!(db.last_sequence(), 4);
+    db.flush();
+    drop(db);
+
+    let db = open(&db_path, &archive);
+    assert_eq!(db.get("a"), Some("2".to_string()));
+    assert_eq!(db.get("b"), Some("1".to_string()));
+    assert_eq!(db.get("d"), Some("1".to_string()));
+
+    db.restore_to(&backup, RestoreTarget::Sequence(u64::MAX))
+        .expect("Failed to restore");
+    assert_eq!(db.get("a"), Some("2".to_string()));
+    assert_eq!(db.get("d"), Some("1".to_string()));
+}
+
+#[test]
+fn test_checkpoint_opens_as_database() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("source.db");
+    let backup = dir.path().join("backup");
+
+    let options = Options {
+        blob_threshold: Some(16),
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).expect("Failed to open database");
+    db.set("small".to_string(), "1".to_string());
+    db.set("large".to_string(), "x".repeat(100));
+    db.checkpoint(&backup).expect("Failed to checkpoint");
+    assert!(db.checkpoint(&backup).is_err());
+    drop(db);
+
+    let copy = Database::load(&backup).expect("Failed to load checkpoint");
+    assert_eq!(copy.last_sequence(), 2);
+    assert_eq!(copy.get("small"), Some("1".to_string()));
+    assert_eq!(copy.get("large"), Some("x".repeat(100)));
+}
+
+#[test]
+fn test_checkpoint_keeps_table_filters() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("source.db");
+    let backup = dir.path().join("backup");
+    let extractor = Arc::new(DelimitedPrefix::new('/'));
+
+    let options = Options {
+        blob_threshold: Some(16),
+        prefix_extractor: Some(extractor.clone()),
+        block_hash_index: true,
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).expect("Failed to open database");
+    db.set("t/small".to_string(), "1".to_string());
+    db.set("t/large".to_string(), "x".repeat(100));
+    db.checkpoint(&backup).expect("Failed to checkpoint");
+    drop(db);
+
+    let tables: Vec<_> = fs::read_dir(&backup)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "sst"))
+        .collect();
+    assert_eq!(tables.len(), 1);
+
+    // The copy inlines the blob values but keeps the filter and hash index
+    let reader = SSTableReader::new(&tables[0]).expect("Failed to open table");
+    assert_eq!(reader.properties().unwrap().num_blob_refs, 0);
+    assert!(reader.may_contain_prefix(extractor.as_ref(), "t/"));
+    assert!(!reader.may_contain_prefix(extractor.as_ref(), "u/"));
+    let name = b"janql.block.hash_index";
+    let bytes = fs::read(&tables[0]).unwrap();
+    assert!(bytes.windows(name.len()).any(|w| w == name));
+}
+
+#[test]
+fn test_restore_rejects_target_before_checkpoint() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("early.db");
+    let archive = dir.path().join("archive");
+    let backup = dir.path().join("backup");
+
+    let db = open(&db_path, &archive);
+    db.set("a".to_string(), "1".to_string()); // 1
+    thread::sleep(Duration::from_millis(2));
+    let before_checkpoint = SystemTime::now();
+    thread::sleep(Duration::from_millis(2));
+    db.set("a".to_string(), "2".to_string()); // 2
+    db.checkpoint(&backup).expect("Failed to checkpoint");
+    db.set("a".to_string(), "3".to_string()); // 3
+
+    for target in [
+        RestoreTarget::Sequence(1),
+        RestoreTarget::Timestamp(before_checkpoint),
+    ] {
+        let err = db.restore_to(&backup, target).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(db.last_sequence(), 3);
+        assert_eq!(db.get("a"), Some("3".to_string()));
+    }
+
+    // The checkpoint itself is a valid target
+    db.restore_to(&backup, RestoreTarget::Sequence(2))
+        .expect("Failed to restore");
+    assert_eq!(db.get("a"), Some("2".to_string()));
+}
+
+#[test]
+fn test_restore_rejects_archive_gap() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("gap.db");
+    let archive = dir.path().join("archive");
+    let backup = dir.path().join("backup");
+
+    let options = Options {
+        wal_archive_dir: Some(archive.clone()),
+        wal_archive_ttl: Some(Duration::from_millis(100)),
+        ..Options::default()
+    };
+    let db = Database::open(&db_path, options).expect("Failed to open database");
+    db.set("a".to_string(), "1".to_string()); // 1
+    db.checkpoint(&backup).expect("Failed to checkpoint");
+    db.set("a".to_string(), "2".to_string()); // 2
+    db.flush();
+    thread::sleep(Duration::from_millis(200));
+
+    // Archiving this segment expires the one holding write 2
+    db.set("a".to_string(), "3".to_string()); // 3
+    db.flush();
+    db.set("a".to_string(), "4".to_string()); // 4, in the live WAL
+
+    for target in [
+        RestoreTarget::Sequence(u64::MAX),
+        RestoreTarget::Sequence(2),
+        RestoreTarget::Timestamp(SystemTime::now()),
+    ] {
+        let err = db.restore_to(&backup, target).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(db.last_sequence(), 4);
+        assert_eq!(db.get("a"), Some("4".to_string()));
+    }
+
+    // Nothing is missing up to the checkpoint
+    db.restore_to(&backup, RestoreTarget::Sequence(1))
+        .expect("Failed to restore");
+    assert_eq!(db.get("a"), Some("1".to_string()));
+}
+
+#[test]
+fn test_restore_keeps_discarded_writes_in_archive_generation() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("generation.db");
+    let archive = dir.path().join("archive");
+    let backup = dir.path().join("backup");
+
+    let db = open(&db_path, &archive);
+    db.set("a".to_string(), "1".to_string()); // 1
+    db.checkpoint(&backup).expect("Failed to checkpoint");
+    db.set("a".to_string(), "2".to_string()); // 2
+    db.flush();
+    db.set("a".to_string(), "3".to_string()); // 3
+
+    db.restore_to(&backup, RestoreTarget::Sequence(1))
+        .expect("Failed to restore");
+    assert_eq!(db.get("a"), Some("1".to_string()));
+
+    // The cut segments were saved whole before writes 2 and 3 were dropped
+    let generation = archive.join("generation_000001");
+    let saved: Vec<_> = fs::read_dir(&generation)
+        .expect("Missing archive generation")
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert!(!saved.is_empty());
+
+    // Rolling forward again after moving them back
+    for path in saved {
+        fs::rename(&path, archive.join(path.file_name().unwrap())).unwrap();
+    }
+    db.restore_to(&backup, RestoreTarget::Sequence(u64::MAX))
+        .expect("Failed to restore");
+    assert_eq!(db.get("a"), Some("3".to_string()));
+}
@@ -1,7 +1,19 @@
-use janql::sstable::{SSTableBuilder, SSTableReader, SearchResult};
+use janql::cache::BlockCache;
+use janql::prefix::{DelimitedPrefix, FixedPrefix};
+use janql::sstable::{SSTableBuilder, SSTableReader, SearchResult, SearchResultRef};
 use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Arc;
 use tempfile::TempDir;
 
+fn scan(reader: &SSTableReader, start: &str, end: &str) -> Vec<(String, String)> {
+    reader
+        .scan(start, end)
+        .expect("Failed to scan")
+        .collect::<Result<_, _>>()
+        .expect("Failed to scan")
+}
+
 #[test]
 fn test_sstable_segmentation() {
     let dir = TempDir::new().expect("Failed to create temp dir");
@@ -23,12 +35,12 @@ fn test_sstable_segmentation() {
     builder.finish().expect("Failed to finish");
 
     // Read back
-    let mut reader = SSTableReader::new(&sst_path).expect("Failed to open reader");
+    let reader = SSTableReader::new(&sst_path).expect("Failed to open reader");
 
     // Check index size (should have multiple blocks)
-    // We don't expose index size directly but we can check if we can read all keys.
-    println!("Index size: {}", reader.index.len());
-    assert!(reader.index.len() > 1, "Should have multiple blocks");
+    assert!(reader.num_data_blocks() > 1, "Should have multiple blocks");
+    let index = reader.index().expect("Index should be a full index");
+    assert_eq!(index.len(), reader.num_data_blocks());
 
     // Verify all keys
     for (key, value) in &data {
@@ -39,10 +51,379 @@ fn test_sstable_segmentation() {
     // Verify range scan
     let start = "key0010";
     let end = "key0020";
-    let range_res = reader.scan(start, end).expect("Failed to scan");
+    let range_res = scan(&reader, start, end);
 
     assert_eq!(range_res.len(), 11); // 10 to 20 inclusive
     for (k, v) in range_res {
         assert_eq!(v, *data.get(&k).unwrap());
     }
 }
+
+#[test]
+fn test_sstable_properties() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("props.sst");
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    builder.set_sequence_range(10, 13);
+    builder.add("apple", "red").expect("Failed to add");
+    builder.delete("banana").expect("Failed to delete");
+    builder.add("cherry", "dark").expect("Failed to add");
+    builder.finish().expect("Failed to finish");
+
+    let reader = SSTableReader::new(&sst_path).expect("Failed to open reader");
+    let props = reader.properties().expect("Missing properties");
+
+    assert_eq!(props.smallest_key, "apple");
+    assert_eq!(props.largest_key, "cherry");
+    assert_eq!(props.num_entries, 3);
+    assert_eq!(props.num_tombstones, 1);
+    assert_eq!(props.raw_key_bytes, 5 + 6 + 6);
+    assert_eq!(props.raw_value_bytes, 3 + 4);
+    assert_eq!((props.min_sequence, props.max_sequence), (10, 13));
+
+    assert!(reader.may_contain("banana"));
+    assert!(!reader.may_contain("aardvark"));
+    assert!(!reader.may_contain("date"));
+    assert!(reader.overlaps("b", "c"));
+    assert!(!reader.overlaps("d", "z"));
+}
+
+#[test]
+fn test_sstable_legacy_footer() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("legacy.sst");
+
+    // Data block followed by index and a bare 8-byte index offset
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(b"key");
+    bytes.extend_from_slice(&5u32.to_le_bytes());
+    bytes.extend_from_slice(b"value");
+    let index_offset = bytes.len() as u64;
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(b"key");
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&index_offset.to_le_bytes());
+    std::fs::write(&sst_path, bytes).unwrap();
+
+    let reader = SSTableReader::new(&sst_path).expect("Failed to open reader");
+    assert!(reader.properties().is_none());
+    assert!(reader.may_contain("anything"));
+    assert_eq!(
+        reader.get("key").unwrap(),
+        SearchResult::Found("value".to_string())
+    );
+}
+
+#[test]
+fn test_sstable_mmap_reader() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("mmap.sst");
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    for i in 0..100 {
+        let key = format!("key{:04}", i);
+        if i % 10 == 0 {
+            builder.delete(&key).expect("Failed to delete");
+        } else {
+            builder.add(&key, &"v".repeat(100)).expect("Failed to add");
+        }
+    }
+    builder.finish().expect("Failed to finish");
+
+    let reader = SSTableReader::with_mmap(&sst_path).expect("Failed to open reader");
+    assert!(reader.num_data_blocks() > 1, "Should have multiple blocks");
+
+    assert_eq!(
+        reader.get_ref("key0001").unwrap(),
+        SearchResultRef::Found("v".repeat(100).as_str())
+    );
+    assert_eq!(reader.get_ref("key0010").unwrap(), SearchResultRef::Deleted);
+    assert_eq!(
+        reader.get_ref("key9999").unwrap(),
+        SearchResultRef::NotFound
+    );
+    assert_eq!(
+        reader.get("key0099").unwrap(),
+        SearchResult::Found("v".repeat(100))
+    );
+
+    let range_res = scan(&reader, "key0010", "key0020");
+    assert_eq!(range_res.len(), 9); // key0010 and key0020 are tombstones
+
+    let mmap_entries: Vec<_> = reader.into_iter().map(|e| e.unwrap()).collect();
+    let file_entries: Vec<_> = SSTableReader::new(&sst_path)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.unwrap())
+        .collect();
+    assert_eq!(mmap_entries.len(), 100);
+    assert_eq!(mmap_entries, file_entries);
+}
+
+#[test]
+fn test_sstable_get_ref_requires_mmap() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("plain.sst");
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    builder.add("key", "value").expect("Failed to add");
+    builder.finish().expect("Failed to finish");
+
+    let reader = SSTableReader::new(&sst_path).expect("Failed to open reader");
+    let err = reader.get_ref("key").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn test_sstable_mmap_corrupt_handle() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("corrupt.sst");
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    builder.set_index_partition_size(64);
+    for i in 0..100 {
+        builder
+            .add(&format!("key{:04}", i), &"v".repeat(100))
+            .expect("Failed to add");
+    }
+    builder.finish().expect("Failed to finish");
+
+    // Stretch the first block's handle in its index partition past the file
+    let mut bytes = fs::read(&sst_path).unwrap();
+    let mut entry = 7u32.to_le_bytes().to_vec();
+    entry.extend_from_slice(b"key0000");
+    entry.extend_from_slice(&0u64.to_le_bytes());
+    let pos = bytes
+        .windows(entry.len())
+        .position(|w| w == entry.as_slice())
+        .expect("Index entry not found")
+        + entry.len();
+    let len = bytes.len() as u64 + 100;
+    bytes[pos..pos + 8].copy_from_slice(&len.to_le_bytes());
+    fs::write(&sst_path, bytes).unwrap();
+
+    let reader = SSTableReader::with_mmap(&sst_path).expect("Failed to open reader");
+    let err = reader.get("key0000").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let err = reader
+        .scan("key0000", "key0001")
+        .unwrap()
+        .find_map(Result::err)
+        .expect("Scan should fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(reader.into_iter().any(|e| e.is_err()));
+}
+
+#[test]
+fn test_sstable_partitioned_index() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("partitioned.sst");
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    builder.set_index_partition_size(64);
+    let mut data = BTreeMap::new();
+    for i in 0..1000 {
+        let key = format!("key{:04}", i);
+        let value = "v".repeat(100);
+        data.insert(key.clone(), value.clone());
+        builder.add(&key, &value).expect("Failed to add");
+    }
+    builder.finish().expect("Failed to finish");
+
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+    let readers = [
+        SSTableReader::new(&sst_path).expect("Failed to open reader"),
+        SSTableReader::with_cache(&sst_path, cache.clone()).expect("Failed to open reader"),
+        SSTableReader::with_mmap(&sst_path).expect("Failed to open reader"),
+    ];
+
+    for reader in readers {
+        assert!(reader.num_data_blocks() > 20);
+        assert!(reader.index().is_none());
+
+        for (key, value) in &data {
+            let res = reader.get(key).expect("Failed to get");
+            assert_eq!(res, SearchResult::Found(value.clone()));
+        }
+        assert_eq!(reader.get("a").unwrap(), SearchResult::NotFound);
+        assert_eq!(reader.get("key0500a").unwrap(), SearchResult::NotFound);
+        assert_eq!(reader.get("zzz").unwrap(), SearchResult::NotFound);
+
+        let range_res = scan(&reader, "key0495", "key0505");
+        assert_eq!(range_res.len(), 11);
+        assert_eq!(range_res[0].0, "key0495");
+
+        let entries: Vec<_> = reader.into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 1000);
+    }
+
+    // Index partitions were served through the cache
+    assert!(cache.stats().hits > 0);
+}
+
+#[test]
+fn test_sstable_scan_past_last_key() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let full_path = dir.path().join("full.sst");
+    let partitioned_path = dir.path().join("partitioned.sst");
+
+    for (path, partition_size) in [(&full_path, None), (&partitioned_path, Some(64))] {
+        let mut builder = SSTableBuilder::new(path).expect("Failed to create builder");
+        if let Some(size) = partition_size {
+            builder.set_index_partition_size(size);
+        }
+        for i in 0..200 {
+            let key = format!("key{:04}", i);
+            if i % 10 == 0 {
+                builder.delete(&key).expect("Failed to delete");
+            } else {
+                builder.add(&key, &"v".repeat(100)).expect("Failed to add");
+            }
+        }
+        builder.finish().expect("Failed to finish");
+    }
+
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+    for path in [&full_path, &partitioned_path] {
+        let readers = [
+            SSTableReader::new(path).expect("Failed to open reader"),
+            SSTableReader::with_cache(path, cache.clone()).expect("Failed to open reader"),
+            SSTableReader::with_mmap(path).expect("Failed to open reader"),
+        ];
+
+        for reader in readers {
+            assert!(reader.num_data_blocks() > 1);
+
+            // Ends past the last key, which sorts below the index entries
+            let tail = scan(&reader, "key0150", "zzz");
+            assert_eq!(tail.len(), 45);
+            assert_eq!(tail.first().unwrap().0, "key0151");
+            assert_eq!(tail.last().unwrap().0, "key0199");
+
+            let all = scan(&reader, "", "\u{10FFFF}");
+            assert_eq!(all.len(), 180);
+            assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+
+            assert!(scan(&reader, "key1000", "zzz").is_empty());
+            assert!(scan(&reader, "a", "b").is_empty());
+            assert_eq!(scan(&reader, "key0042", "key0042").len(), 1);
+        }
+    }
+}
+
+#[test]
+fn test_sstable_scan_reads_blocks_lazily() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("lazy.sst");
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    for i in 0..1000 {
+        builder
+            .add(&format!("key{:04}", i), &"v".repeat(100))
+            .expect("Failed to add");
+    }
+    builder.finish().expect("Failed to finish");
+
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+    let reader =
+        SSTableReader::with_cache(&sst_path, cache.clone()).expect("Failed to open reader");
+    assert!(reader.num_data_blocks() > 20);
+
+    let mut iter = reader.scan("", "\u{10FFFF}").expect("Failed to scan");
+    assert_eq!(cache.stats().misses, 0);
+    assert_eq!(iter.next().unwrap().unwrap().0, "key0000");
+    assert_eq!(cache.stats().misses, 1);
+
+    assert_eq!(iter.count(), 999);
+    assert_eq!(cache.stats().misses, reader.num_data_blocks() as u64);
+}
+
+#[test]
+fn test_sstable_prefix_filter() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sst_path = dir.path().join("prefix.sst");
+    let extractor = Arc::new(DelimitedPrefix::new('/'));
+
+    let mut builder = SSTableBuilder::new(&sst_path).expect("Failed to create builder");
+    builder.set_prefix_extractor(extractor.clone());
+    for tenant in (0..100).step_by(2) {
+        for i in 0..5 {
+            let key = format!("tenant{:03}/item{}", tenant, i);
+            builder.add(&key, "v").expect("Failed to add");
+        }
+    }
+    builder.add("unprefixed", "v").expect("Failed to add");
+    builder.finish().expect("Failed to finish");
+
+    let reader = SSTableReader::new(&sst_path).expect("Failed to open reader");
+    for tenant in (0..100).step_by(2) {
+        assert!(reader.may_contain_prefix(extractor.as_ref(), &format!("tenant{:03}/", tenant)));
+        assert!(
+            reader.may_contain_prefix(extractor.as_ref(), &format!("tenant{:03}/item", tenant))
+        );
+    }
+    let false_positives = (1..100)
+        .step_by(2)
+        .filter(|t| reader.may_contain_prefix(extractor.as_ref(), &format!("tenant{:03}/", t)))
+        .count();
+    assert!(false_positives < 5, "{} false positives", false_positives);
+
+    // Queries without a full prefix, or from another extractor, can't be ruled out
+    assert!(reader.may_contain_prefix(extractor.as_ref(), "tenant001"));
+    assert!(reader.may_contain_prefix(&FixedPrefix::new(10), "tenant001/"));
+}
+
+#[test]
+fn test_sstable_block_hash_index() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let hashed_path = dir.path().join("hashed.sst");
+    let plain_path = dir.path().join("plain.sst");
+
+    for (path, hashed) in [(&hashed_path, true), (&plain_path, false)] {
+        let mut builder = SSTableBuilder::new(path).expect("Failed to create builder");
+        builder.set_block_hash_index(hashed);
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            if i % 7 == 0 {
+                builder.delete(&key).expect("Failed to delete");
+            } else {
+                builder
+                    .add(&key, &format!("value{}", i))
+                    .expect("Failed to add");
+            }
+        }
+        builder.finish().expect("Failed to finish");
+    }
+
+    // The trailer makes the blocks a little larger
+    assert!(fs::metadata(&hashed_path).unwrap().len() > fs::metadata(&plain_path).unwrap().len());
+
+    for path in [&hashed_path, &plain_path] {
+        let reader = SSTableReader::new(path).expect("Failed to open reader");
+        let mapped = SSTableReader::with_mmap(path).expect("Failed to open reader");
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            let expected = if i % 7 == 0 {
+                SearchResult::Deleted
+            } else {
+                SearchResult::Found(format!("value{}", i))
+            };
+            assert_eq!(reader.get(&key).unwrap(), expected);
+            assert_eq!(
+                mapped.get_ref(&key).unwrap() == SearchResultRef::Deleted,
+                i % 7 == 0
+            );
+        }
+        for missing in ["key0000a", "key9999", "a", "key05"] {
+            assert_eq!(reader.get(missing).unwrap(), SearchResult::NotFound);
+            assert_eq!(mapped.get_ref(missing).unwrap(), SearchResultRef::NotFound);
+        }
+
+        assert_eq!(scan(&reader, "key0100", "key0199").len(), 86);
+        let entries: Vec<_> = reader.into_iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 1000);
+        assert_eq!(entries[999].0, "key0999");
+    }
+}
@@ -0,0 +1,416 @@
+use janql::wal::{
+    RecoveryReport, WALIterator, WalRecoveryMode, WalStats, WalSyncMode, segment_path,
+};
+use janql::{Database, Options, SstFileWriter, WriteOptions};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open(path: &Path, wal_recovery_mode: WalRecoveryMode) -> std::io::Result<Database> {
+    Database::open(
+        path,
+        Options {
+            wal_recovery_mode,
+            ..Options::default()
+        },
+    )
+}
+
+/// Writes `count` records and returns the WAL offset where each one starts.
+fn write_records(path: &Path, count: usize) -> Vec<u64> {
+    let db = Database::new(path);
+    let wal_path = segment_path(path, 1);
+    let mut offsets = Vec::new();
+    for i in 0..count {
+        offsets.push(fs::metadata(&wal_path).unwrap().len());
+        db.set(format!("key{}", i), format!("value{}", i));
+    }
+    offsets
+}
+
+fn flip_byte(path: &Path, offset: u64) {
+    let mut data = fs::read(path).unwrap();
+    data[offset as usize] ^= 0xff;
+    fs::write(path, data).unwrap();
+}
+
+#[test]
+fn test_torn_tail_is_dropped() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("torn.db");
+    let wal_path = segment_path(&db_path, 1);
+    let offsets = write_records(&db_path, 5);
+
+    // A crash halfway through appending the last record
+    let len = fs::metadata(&wal_path).unwrap().len();
+    let torn_len = (offsets[4] + len) / 2;
+    OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .unwrap()
+        .set_len(torn_len)
+        .unwrap();
+
+    let err = open(&db_path, WalRecoveryMode::AbsoluteConsistency)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let db = open(&db_path, WalRecoveryMode::TolerateCorruptedTail).expect("Failed to open");
+    assert_eq!(
+        *db.recovery_report(),
+        RecoveryReport {
+            records_replayed: 4,
+            records_dropped: 1,
+            bytes_dropped: torn_len - offsets[4],
+        }
+    );
+    assert_eq!(db.get("key3"), Some("value3".to_string()));
+    assert_eq!(db.get("key4"), None);
+
+    // The tail was cut off, so new records are readable after it
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), offsets[4]);
+    db.set("key5".to_string(), "value5".to_string());
+    drop(db);
+
+    let db = open(&db_path, WalRecoveryMode::AbsoluteConsistency).expect("Failed to open");
+    assert_eq!(db.recovery_report().records_dropped, 0);
+    assert_eq!(db.get("key5"), Some("value5".to_string()));
+}
+
+#[test]
+fn test_corrupted_record_in_the_middle() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("corrupt.db");
+    let offsets = write_records(&db_path, 5);
+
+    // Damage the value of the third record
+    flip_byte(&segment_path(&db_path, 1), offsets[3] - 1);
+
+    for mode in [
+        WalRecoveryMode::AbsoluteConsistency,
+        WalRecoveryMode::TolerateCorruptedTail,
+    ] {
+        let err = open(&db_path, mode).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    let db = open(&db_path, WalRecoveryMode::SkipCorruptedRecords).expect("Failed to open");
+    assert_eq!(db.recovery_report().records_replayed, 4);
+    assert_eq!(db.recovery_report().records_dropped, 1);
+    assert_eq!(db.recovery_report().bytes_dropped, offsets[3] - offsets[2]);
+    assert_eq!(db.get("key1"), Some("value1".to_string()));
+    assert_eq!(db.get("key2"), None);
+    assert_eq!(db.get("key4"), Some("value4".to_string()));
+}
+
+#[test]
+fn test_corrupted_last_record() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("corrupt_tail.db");
+    write_records(&db_path, 3);
+
+    let wal_path = segment_path(&db_path, 1);
+    flip_byte(&wal_path, fs::metadata(&wal_path).unwrap().len() - 1);
+
+    let db = open(&db_path, WalRecoveryMode::TolerateCorruptedTail).expect("Failed to open");
+    assert_eq!(db.recovery_report().records_replayed, 2);
+    assert_eq!(db.recovery_report().records_dropped, 1);
+    assert_eq!(db.get("key2"), None);
+}
+
+#[test]
+fn test_legacy_wal_is_replayed() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("legacy.db");
+    fs::create_dir_all(&db_path).unwrap();
+
+    // Unframed records: [op][key_len][key][val_len][value] / [op][key_len][key]
+    let mut log = Vec::new();
+    for (key, value) in [("a", Some("1")), ("b", Some("2")), ("a", None)] {
+        log.push(if value.is_some() { 1 } else { 2 });
+        log.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        log.extend_from_slice(key.as_bytes());
+        if let Some(value) = value {
+            log.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            log.extend_from_slice(value.as_bytes());
+        }
+    }
+    let mut file = fs::File::create(db_path.join("wal.log")).unwrap();
+    file.write_all(&log).unwrap();
+    drop(file);
+
+    let db = Database::load(&db_path).expect("Failed to load database");
+    assert_eq!(db.recovery_report().records_replayed, 3);
+    assert_eq!(db.get("a"), None);
+    assert_eq!(db.get("b"), Some("2".to_string()));
+    db.set("c".to_string(), "3".to_string());
+    drop(db);
+
+    let db = open(&db_path, WalRecoveryMode::AbsoluteConsistency).expect("Failed to open");
+    assert_eq!(db.get("b"), Some("2".to_string()));
+    assert_eq!(db.get("c"), Some("3".to_string()));
+}
+
+#[test]
+fn test_flushed_segments_are_not_replayed() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("flushed.db");
+
+    let db = Database::new(&db_path);
+    db.set("a".to_string(), "old".to_string());
+    let stale = fs::read(segment_path(&db_path, 1)).unwrap();
+    db.flush();
+    db.set("a".to_string(), "new".to_string());
+    drop(db);
+
+    // A crash after the flush was recorded but before its segment was deleted
+    fs::write(segment_path(&db_path, 1), stale).unwrap();
+
+    let db = Database::load(&db_path).expect("Failed to load database");
+    assert_eq!(db.recovery_report().records_replayed, 1);
+    assert_eq!(db.get("a"), Some("new".to_string()));
+    assert!(!segment_path(&db_path, 1).exists());
+}
+
+#[test]
+fn test_live_segments_are_replayed_in_order() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("segments.db");
+    let other_path = dir.path().join("other.db");
+
+    let db = Database::new(&db_path);
+    db.set("a".to_string(), "1".to_string());
+    db.set("b".to_string(), "1".to_string());
+    drop(db);
+
+    // A crash after the log moved on to a new segment but before the flush
+    // was recorded leaves two live segments
+    let db = Database::new(&other_path);
+    db.set("a".to_string(), "2".to_string());
+    drop(db);
+    fs::copy(segment_path(&other_path, 1), segment_path(&db_path, 2)).unwrap();
+
+    let db = Database::load(&db_path).expect("Failed to load database");
+    assert_eq!(db.recovery_report().records_replayed, 3);
+    assert_eq!(db.get("a"), Some("2".to_string()));
+    assert_eq!(db.get("b"), Some("1".to_string()));
+
+    db.flush();
+    assert!(!segment_path(&db_path, 1).exists());
+    assert!(!segment_path(&db_path, 2).exists());
+    assert!(segment_path(&db_path, 3).exists());
+}
+
+#[test]
+fn test_records_carry_sequence_and_timestamp() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("sequence.db");
+    let sst_path = dir.path().join("external.sst");
+
+    let mut writer = SstFileWriter::create(&sst_path).unwrap();
+    writer.put("x", "1").unwrap();
+    writer.finish().unwrap();
+
+    // The ingested table takes sequence 2 without a WAL record
+    let db = Database::new(&db_path);
+    db.set("a".to_string(), "1".to_string());
+    db.ingest_external_files(&[&sst_path]).unwrap();
+    db.del("a");
+    assert_eq!(db.last_sequence(), 3);
+    drop(db);
+
+    let records: Vec<_> = WALIterator::new(segment_path(&db_path, 1))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        (records[0].sequence, records[0].value.as_deref()),
+        (1, Some("1"))
+    );
+    assert_eq!(
+        (records[1].sequence, records[1].value.as_deref()),
+        (3, None)
+    );
+    assert!(records[0].timestamp > 0);
+    assert!(records[0].timestamp <= records[1].timestamp);
+
+    let db = Database::load(&db_path).expect("Failed to load database");
+    assert_eq!(db.last_sequence(), 3);
+    assert_eq!(db.get("a"), None);
+    assert_eq!(db.get("x"), Some("1".to_string()));
+}
+
+fn open_with_sync_mode(path: &Path, wal_sync_mode: WalSyncMode) -> Database {
+    Database::open(
+        path,
+        Options {
+            wal_sync_mode,
+            ..Options::default()
+        },
+    )
+    .expect("Failed to open database")
+}
+
+#[test]
+fn test_wal_sync_modes() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let cases = [
+        (WalSyncMode::Always, 10, 0),
+        (WalSyncMode::EveryN(4), 2, 2),
+        (WalSyncMode::Interval(Duration::from_secs(3600)), 0, 10),
+        (WalSyncMode::OnFlushOnly, 0, 10),
+        (WalSyncMode::Disabled, 0, 0),
+    ];
+
+    for (i, (mode, syncs, unsynced_records)) in cases.into_iter().enumerate() {
+        let db_path = dir.path().join(format!("sync_{}.db", i));
+        let db = open_with_sync_mode(&db_path, mode);
+        for j in 0..10 {
+            db.set(format!("key{}", j), "value".to_string());
+        }
+        assert_eq!(
+            db.wal_stats(),
+            WalStats {
+                syncs,
+                unsynced_records
+            },
+            "{:?}",
+            mode
+        );
+
+        db.sync_wal().expect("Failed to sync");
+        assert_eq!(db.wal_stats().unsynced_records, 0);
+        drop(db);
+
+        // Short of an OS crash, every logged write survives
+        let db = open_with_sync_mode(&db_path, mode);
+        let expected = (mode != WalSyncMode::Disabled).then(|| "value".to_string());
+        assert_eq!(db.get("key9"), expected, "{:?}", mode);
+    }
+}
+
+#[test]
+fn test_write_options_override_sync_mode() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let sync = WriteOptions { sync: Some(true) };
+    let no_sync = WriteOptions { sync: Some(false) };
+
+    let db = open_with_sync_mode(&dir.path().join("lazy.db"), WalSyncMode::OnFlushOnly);
+    db.set("a".to_string(), "1".to_string());
+    db.set_with_options("b".to_string(), "2".to_string(), &sync);
+    assert_eq!(db.wal_stats().syncs, 1);
+    assert_eq!(db.wal_stats().unsynced_records, 0);
+    db.del_with_options("a", &sync);
+    assert_eq!(db.wal_stats().syncs, 2);
+
+    let db = open_with_sync_mode(&dir.path().join("eager.db"), WalSyncMode::Always);
+    db.set_with_options("a".to_string(), "1".to_string(), &no_sync);
+    db.batch_set_with_options(vec![("b".to_string(), "2".to_string())], &no_sync);
+    assert_eq!(db.wal_stats().syncs, 0);
+    assert_eq!(db.wal_stats().unsynced_records, 2);
+    db.set("c".to_string(), "3".to_string());
+    assert_eq!(db.wal_stats().syncs, 1);
+}
+
+#[test]
+fn test_disabled_wal_keeps_flushed_writes_only() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("no_wal.db");
+
+    let db = open_with_sync_mode(&db_path, WalSyncMode::Disabled);
+    db.set("flushed".to_string(), "1".to_string());
+    db.flush();
+    db.set("unflushed".to_string(), "2".to_string());
+    assert_eq!(fs::metadata(segment_path(&db_path, 2)).unwrap().len(), 8);
+    drop(db);
+
+    let db = open_with_sync_mode(&db_path, WalSyncMode::Disabled);
+    assert_eq!(db.get("flushed"), Some("1".to_string()));
+    assert_eq!(db.get("unflushed"), None);
+}
+
+#[test]
+fn test_group_commit() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let db_path = dir.path().join("group.db");
+    let threads = 8;
+    let writes_per_thread = 250;
+
+    let db = Arc::new(open_with_sync_mode(&db_path, WalSyncMode::Always));
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                for i in 0..writes_per_thread {
+                    db.set(format!("t{}_key{}", t, i), format!("value{}", i));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Writer panicked");
+    }
+
+    // Every write was synced before returning
+    let stats = db.wal_stats();
+    assert_eq!(stats.unsynced_records, 0);
+    assert_eq!(db.last_sequence(), (threads * writes_per_thread) as u64);
+    drop(db);
+
+    let db = open_with_sync_mode(&db_path, WalSyncMode::Always);
+    assert_eq!(
+        db.recovery_report().records_replayed,
+        (threads * writes_per_thread) as u64
+    );
+    for t in 0..threads {
+        for i in 0..writes_per_thread {
+            assert_eq!(
+                db.get(&format!("t{}_key{}", t, i)),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+}
+
+#[cfg(feature = "failpoints")]
+#[test]
+fn test_group_commit_shares_syncs() {
+    let dir = TempDir::new().expect("Failed to create temp dir");
+    let threads = 8;
+
+    // The first leader holds its commit long enough for the other writers
+    // to queue up behind it
+    let options = Options {
+        wal_sync_mode: WalSyncMode::Always,
+        commit_delay: Some(Duration::from_millis(200)),
+        ..Options::default()
+    };
+    let db = Arc::new(
+        Database::open(dir.path().join("group.db"), options).expect("Failed to open database"),
+    );
+    let barrier = Arc::new(std::sync::Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let db = Arc::clone(&db);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                db.set(format!("key{}", t), format!("value{}", t));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Writer panicked");
+    }
+
+    let stats = db.wal_stats();
+    assert_eq!(stats.unsynced_records, 0);
+    assert!(stats.syncs < threads as u64);
+    assert_eq!(db.last_sequence(), threads as u64);
+}